@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+
+use crate::config::AecConfig;
+
+// Normalized LMS adaptive filter, estimates how the played audio shows up in the
+// microphone signal and subtracts it
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    history: VecDeque<f32>,
+    energy: f32,
+    step_size: f32,
+}
+impl EchoCanceller {
+    pub fn new(config: &AecConfig, sample_rate: u32) -> Self {
+        let taps = ((sample_rate as f32 * config.filter_ms as f32 / 1000.) as usize).max(1);
+        EchoCanceller {
+            weights: vec![0.; taps],
+            history: VecDeque::from(vec![0.; taps]),
+            energy: 0.,
+            step_size: config.step_size,
+        }
+    }
+
+    pub fn process(&mut self, input: f32, reference: f32) -> f32 {
+        let oldest = self.history.pop_back().unwrap_or(0.);
+        self.history.push_front(reference);
+        self.energy = (self.energy + reference * reference - oldest * oldest).max(0.);
+
+        // Nothing playing, nothing to cancel
+        if self.energy < 1e-6 {
+            return input;
+        }
+
+        let estimate: f32 = self.weights.iter().zip(&self.history).map(|(w, x)| w * x).sum();
+        let error = input - estimate;
+
+        let adjustment = self.step_size * error / (self.energy + 1e-6);
+        self.weights.iter_mut().zip(&self.history).for_each(|(w, x)| *w += adjustment * x);
+
+        error
+    }
+}
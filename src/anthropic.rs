@@ -0,0 +1,208 @@
+use anyhow::{Result, Context, bail};
+use serde_json::{Value, json};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::chat::{Backend, Entry, FunctionCall, ToolCall};
+use crate::llm::{Completion, LanguageModel, RequestOptions, Usage, check_status, read_lines};
+
+const API_VERSION: &str = "2023-06-01";
+// The Messages API won't go without one
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+// Anthropic's Messages API, for Claude
+pub struct Anthropic {
+    backend: Backend,
+    client: reqwest::Client,
+}
+impl Anthropic {
+    pub fn new(backend: &Backend, client: &reqwest::Client) -> Self {
+        Anthropic { backend: backend.clone(), client: client.clone() }
+    }
+
+    fn messages_url(&self) -> String {
+        let url = match self.backend.url.trim_end_matches('/') {
+            "" => "https://api.anthropic.com",
+            url => url,
+        };
+        match url.ends_with("/v1/messages") {
+            true => url.to_string(),
+            false => format!("{}/v1/messages", url),
+        }
+    }
+
+    fn messages_request(&self, messages: &[Entry], tools: &[Value], stream: bool) -> Value {
+        let backend = &self.backend;
+        let (system, messages) = split_system(messages);
+        let mut request = json!({
+            "model": backend.model,
+            "messages": messages,
+            "max_tokens": backend.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        });
+        if !system.is_empty() {
+            request["system"] = json!(system);
+        }
+        // OpenAI tools API schemas, which only differ in their layout
+        if !tools.is_empty() {
+            request["tools"] = tools.iter()
+                .map(|tool| json!({
+                    "name": tool["function"]["name"],
+                    "description": tool["function"]["description"],
+                    "input_schema": tool["function"]["parameters"],
+                }))
+                .collect();
+        }
+        if let Some(temperature) = backend.temperature {
+            request["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = backend.top_p {
+            request["top_p"] = json!(top_p);
+        }
+        if stream {
+            request["stream"] = json!(true);
+        }
+        request
+    }
+
+    async fn complete(&self, messages: &[Entry], tools: &[Value], deltas: Option<UnboundedSender<String>>) -> Result<(Entry, Usage)> {
+        let mut request = self.client.post(self.messages_url())
+            .header("anthropic-version", API_VERSION);
+        if let Some(key) = &self.backend.api_key {
+            request = request.header("x-api-key", key.trim());
+        }
+        let res = request
+            .json(&self.messages_request(messages, tools, deltas.is_some()))
+            .send()
+            .await?;
+        let res = check_status(res).await?;
+        let tokens_used = |usage: &Value| Usage {
+            prompt_tokens: usage["input_tokens"].as_u64().unwrap_or(0),
+            completion_tokens: usage["output_tokens"].as_u64().unwrap_or(0),
+        };
+
+        let Some(deltas) = deltas else {
+            let response: Value = res.json().await?;
+            let blocks = response["content"].as_array().context("No content")?;
+            let calls: Vec<ToolCall> = blocks.iter()
+                .filter(|block| block["type"] == "tool_use")
+                .map(|block| tool_call(block, block["input"].to_string()))
+                .collect();
+            if !calls.is_empty() {
+                return Ok((Entry::ToolCalls(calls), tokens_used(&response["usage"])));
+            }
+            let content: String = blocks.iter()
+                .filter(|block| block["type"] == "text")
+                .filter_map(|block| block["text"].as_str())
+                .collect();
+            return Ok((Entry::Assistant(content), tokens_used(&response["usage"])));
+        };
+
+        // Server-sent events: the input tokens come with message_start, the
+        // text in content_block_delta and the output tokens in message_delta.
+        // Tool calls start with content_block_start and their input comes in
+        // pieces of JSON
+        let mut content = String::new();
+        let mut calls: Vec<ToolCall> = Vec::new();
+        let mut tokens = Usage::default();
+        read_lines(res, |line| {
+            let Some(data) = line.strip_prefix("data:") else { return Ok(true) };
+            let event: Value = serde_json::from_str(data.trim()).context("Invalid message event")?;
+            match event["type"].as_str().unwrap_or_default() {
+                "message_start" => tokens.prompt_tokens = tokens_used(&event["message"]["usage"]).prompt_tokens,
+                "content_block_start" if event["content_block"]["type"] == "tool_use" => {
+                    calls.push(tool_call(&event["content_block"], String::new()));
+                },
+                "content_block_delta" => match event["delta"]["type"].as_str() {
+                    Some("input_json_delta") => if let Some(call) = calls.last_mut() {
+                        call.function.arguments.push_str(event["delta"]["partial_json"].as_str().unwrap_or_default());
+                    },
+                    _ => if let Some(delta) = event.pointer("/delta/text").and_then(Value::as_str) {
+                        content.push_str(delta);
+                        let _ = deltas.send(delta.to_string());
+                    },
+                },
+                "message_delta" => tokens.completion_tokens = event.pointer("/usage/output_tokens").and_then(Value::as_u64).unwrap_or(0),
+                "message_stop" => return Ok(false),
+                "error" => bail!("{}", event["error"]),
+                _ => {},
+            }
+            Ok(true)
+        }).await?;
+
+        if !calls.is_empty() {
+            // Tools without parameters get no input at all
+            for call in &mut calls {
+                if call.function.arguments.is_empty() {
+                    call.function.arguments = "{}".to_string();
+                }
+            }
+            return Ok((Entry::ToolCalls(calls), tokens));
+        }
+        Ok((Entry::Assistant(content), tokens))
+    }
+}
+
+fn tool_call(block: &Value, arguments: String) -> ToolCall {
+    ToolCall {
+        id: block["id"].as_str().unwrap_or_default().to_string(),
+        function: FunctionCall { name: block["name"].as_str().unwrap_or_default().to_string(), arguments },
+        ..Default::default()
+    }
+}
+// There's no JSON mode, the prompt has to do
+impl LanguageModel for Anthropic {
+    fn request<'a>(&'a self, messages: &'a [Entry], options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a> {
+        Box::pin(self.complete(messages, &options.tools, deltas))
+    }
+}
+
+// The system prompt is a separate field rather than a role, and the messages
+// have to alternate starting with the user. Leading system entries become the
+// system prompt, later ones (e.g. which language was spoken) are told to the
+// model as user turns, and turns by the same role are merged. Tool calls are
+// tool_use blocks from the assistant, and their results tool_result blocks
+// from the user
+fn split_system(messages: &[Entry]) -> (String, Vec<Value>) {
+    let leading = messages.iter().take_while(|entry| matches!(entry, Entry::System(_))).count();
+    let system = messages[..leading].iter().map(Entry::content).collect::<Vec<_>>().join("\n\n");
+
+    let mut turns: Vec<(&str, Vec<Value>)> = Vec::new();
+    for entry in &messages[leading..] {
+        let (role, blocks) = match entry {
+            Entry::ToolCalls(calls) => ("assistant", calls.iter()
+                .map(|call| json!({
+                    "type": "tool_use",
+                    "id": call.id,
+                    "name": call.function.name,
+                    "input": serde_json::from_str::<Value>(&call.function.arguments).unwrap_or_else(|_| json!({})),
+                }))
+                .collect()),
+            Entry::Tool { call_id, content, .. } => ("user", vec![json!({ "type": "tool_result", "tool_use_id": call_id, "content": content })]),
+            // Empty text blocks aren't allowed
+            Entry::Assistant(content) if content.is_empty() => continue,
+            Entry::Assistant(content) => ("assistant", vec![json!({ "type": "text", "text": content })]),
+            Entry::User(content) | Entry::System(content) => ("user", vec![json!({ "type": "text", "text": content })]),
+            Entry::UserImage { content, image } => {
+                // "data:image/jpeg;base64,..."
+                let (media_type, data) = image.strip_prefix("data:")
+                    .and_then(|image| image.split_once(";base64,"))
+                    .unwrap_or(("image/jpeg", image));
+                ("user", vec![
+                    json!({ "type": "image", "source": { "type": "base64", "media_type": media_type, "data": data } }),
+                    json!({ "type": "text", "text": content }),
+                ])
+            },
+        };
+        match turns.last_mut() {
+            Some((last_role, content)) if *last_role == role => content.extend(blocks),
+            _ => turns.push((role, blocks)),
+        }
+    }
+    if turns.first().is_some_and(|(role, _)| *role == "assistant") {
+        turns.insert(0, ("user", vec![json!({ "type": "text", "text": "(Start)" })]));
+    }
+
+    let turns = turns.into_iter()
+        .map(|(role, content)| json!({ "role": role, "content": content }))
+        .collect();
+    (system, turns)
+}
@@ -0,0 +1,321 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{Result, Context, bail};
+use cpal::{Device, Host, Stream, StreamConfig, SupportedStreamConfig, SampleFormat, SizedSample, FromSample};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ringbuf::{HeapRb, HeapConsumer, HeapProducer};
+use rubato::{FftFixedIn, Resampler};
+
+use crate::config::{AudioConfig, Downmix, NetworkInputConfig};
+use crate::network;
+
+// Everything after the input callback (VAD, wakeword, whisper) runs at 16 kHz
+pub const SAMPLE_RATE: u32 = 16000;
+
+// Devices can be picked by exact name, index, or a unique part of the name
+pub fn input_device(host: &Host, selector: Option<&str>) -> Result<Device> {
+    match selector {
+        Some(selector) => select_device(host.input_devices()?.collect(), selector, "input"),
+        None => host.default_input_device().context("No default input device"),
+    }
+}
+pub fn output_device(host: &Host, selector: Option<&str>) -> Result<Device> {
+    match selector {
+        Some(selector) => select_device(host.output_devices()?.collect(), selector, "output"),
+        None => host.default_output_device().context("No default output device"),
+    }
+}
+
+// A running input stream, mono SAMPLE_RATE audio comes out of the consumer
+pub struct Capture {
+    _source: CaptureSource,
+    pub consumer: HeapConsumer<f32>,
+    failed: Arc<AtomicBool>,
+    ended: Arc<AtomicBool>,
+}
+// Only held to keep the source running
+#[allow(dead_code)]
+enum CaptureSource {
+    Device(Stream),
+    File(JoinHandle<()>),
+    Network(network::Receiver),
+    // Fed from elsewhere, e.g. a satellite connection
+    External,
+}
+impl Capture {
+    pub fn start(host: &Host, config: &AudioConfig, verbosity: u8) -> Result<Self> {
+        match (&config.input_file, &config.network) {
+            (Some(path), _) => Self::start_file(path, config),
+            (None, Some(network)) => Self::start_network(network, config.downmix),
+            (None, None) => Self::start_device(host, config, verbosity),
+        }
+    }
+
+    fn start_device(host: &Host, config: &AudioConfig, verbosity: u8) -> Result<Self> {
+        let device = match input_device(host, config.input_device.as_deref()) {
+            Err(err) if config.fallback_to_default && config.input_device.is_some() => {
+                eprintln!("{:?}", err);
+                eprintln!("Falling back to the default input device");
+                input_device(host, None)?
+            },
+            device => device?,
+        };
+
+        let supported = device.default_input_config()?;
+        let stream_config: StreamConfig = supported.clone().into();
+        let channel_count = stream_config.channels as usize;
+        if verbosity >= 1 {
+            println!(" - {:?}", supported);
+            println!(" - {:?}", device.name());
+        }
+
+        let mut resampler = InputResampler::new(stream_config.sample_rate.0)?;
+        if verbosity >= 1 && stream_config.sample_rate.0 != SAMPLE_RATE {
+            println!("Resampling input from {} Hz to {} Hz", stream_config.sample_rate.0, SAMPLE_RATE);
+        }
+
+        let downmix = config.downmix;
+        if let Downmix::Channel(channel) = downmix {
+            if channel >= channel_count {
+                eprintln!("Input channel {} doesn't exist, the device has {} channels, using the last one", channel, channel_count);
+            }
+        }
+
+        // The input callback only ever pushes into this lock-free queue so it
+        // never blocks on the main loop
+        let (mut producer, consumer) = HeapRb::<f32>::new(SAMPLE_RATE as usize * 2).split();
+        let failed = Arc::new(AtomicBool::new(false));
+        let stream_failed = failed.clone();
+        let stream = build_input_stream(
+            &device,
+            &supported,
+            move |data: &[f32]| {
+                let samples = resampler.process(downmix_samples(data, channel_count, downmix));
+                producer.push_slice(&samples); // Drops samples if the main loop falls behind
+            },
+            move |err| {
+                eprintln!("Stream error: {:?}", err);
+                stream_failed.store(true, Ordering::Relaxed);
+            }
+        )?;
+        stream.play()?;
+
+        Ok(Capture {
+            _source: CaptureSource::Device(stream),
+            consumer,
+            failed,
+            ended: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Feed a WAV file through the pipeline instead of a microphone, at
+    // input_file_speed times realtime
+    fn start_file(path: &str, config: &AudioConfig) -> Result<Self> {
+        let mut reader = hound::WavReader::open(path)
+            .with_context(|| format!("Failed to open input file {:?}", path))?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect::<Result<_, _>>()?
+            },
+        };
+
+        let mut resampler = InputResampler::new(spec.sample_rate)?;
+        let mut audio = resampler.process(downmix_samples(&samples, spec.channels as usize, config.downmix));
+        // Trailing silence so the last command gets finished off
+        audio.extend(std::iter::repeat_n(0., SAMPLE_RATE as usize * 5));
+
+        let (producer, consumer) = HeapRb::<f32>::new(SAMPLE_RATE as usize * 2).split();
+        let ended = Arc::new(AtomicBool::new(false));
+        let file_ended = ended.clone();
+        let speed = config.input_file_speed;
+        let thread = std::thread::spawn(move || {
+            feed_file(audio, producer, speed);
+            file_ended.store(true, Ordering::Relaxed);
+        });
+
+        Ok(Capture {
+            _source: CaptureSource::File(thread),
+            consumer,
+            failed: Arc::new(AtomicBool::new(false)),
+            ended,
+        })
+    }
+
+    fn start_network(network: &NetworkInputConfig, downmix: Downmix) -> Result<Self> {
+        let (producer, consumer) = HeapRb::<f32>::new(SAMPLE_RATE as usize * 2).split();
+        let failed = Arc::new(AtomicBool::new(false));
+        let receiver = network::Receiver::start(network, downmix, producer, failed.clone())?;
+
+        Ok(Capture {
+            _source: CaptureSource::Network(receiver),
+            consumer,
+            failed,
+            ended: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Audio pushed into the other end of the consumer by someone else, ends
+    // once `ended` is set
+    pub fn external(consumer: HeapConsumer<f32>, ended: Arc<AtomicBool>) -> Self {
+        Capture {
+            _source: CaptureSource::External,
+            consumer,
+            failed: Arc::new(AtomicBool::new(false)),
+            ended,
+        }
+    }
+
+    // The device went away or errored, the capture needs to be restarted
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+    // Only file and satellite input ever ends
+    pub fn ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed) && self.consumer.is_empty()
+    }
+}
+
+fn feed_file(audio: Vec<f32>, mut producer: HeapProducer<f32>, speed: f32) {
+    let chunk_length = SAMPLE_RATE as usize / 100;
+    for chunk in audio.chunks(chunk_length) {
+        // Unlike a microphone this can wait for the main loop to catch up
+        let mut remaining = chunk;
+        while !remaining.is_empty() {
+            let pushed = producer.push_slice(remaining);
+            remaining = &remaining[pushed..];
+            if !remaining.is_empty() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        if speed > 0. {
+            std::thread::sleep(Duration::from_secs_f32(0.01 / speed));
+        }
+    }
+}
+
+// Build an input stream in whatever sample format the device uses, handing the
+// callback interleaved f32 samples
+fn build_input_stream<F, E>(device: &Device, supported: &SupportedStreamConfig, callback: F, on_error: E) -> Result<Stream>
+    where F: FnMut(&[f32]) + Send + 'static, E: FnMut(cpal::StreamError) + Send + 'static
+{
+    let config = supported.config();
+    match supported.sample_format() {
+        SampleFormat::F32 => build_stream::<f32, F, E>(device, &config, callback, on_error),
+        SampleFormat::I16 => build_stream::<i16, F, E>(device, &config, callback, on_error),
+        SampleFormat::U16 => build_stream::<u16, F, E>(device, &config, callback, on_error),
+        SampleFormat::I32 => build_stream::<i32, F, E>(device, &config, callback, on_error),
+        SampleFormat::I8 => build_stream::<i8, F, E>(device, &config, callback, on_error),
+        SampleFormat::U8 => build_stream::<u8, F, E>(device, &config, callback, on_error),
+        format => bail!("Unsupported input sample format {:?}", format),
+    }
+}
+fn build_stream<T, F, E>(device: &Device, config: &StreamConfig, mut callback: F, on_error: E) -> Result<Stream>
+    where T: SizedSample, f32: FromSample<T>, F: FnMut(&[f32]) + Send + 'static, E: FnMut(cpal::StreamError) + Send + 'static
+{
+    let mut converted = Vec::new();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            converted.clear();
+            converted.extend(data.iter().map(|sample| sample.to_sample::<f32>()));
+            callback(&converted);
+        },
+        on_error,
+        None
+    )?;
+    Ok(stream)
+}
+
+fn select_device(devices: Vec<Device>, selector: &str, kind: &str) -> Result<Device> {
+    let names: Vec<String> = devices.iter()
+        .map(|device| device.name().unwrap_or_else(|_| "<unknown>".to_string()))
+        .collect();
+
+    let exact = names.iter().position(|name| name == selector);
+    let index = selector.parse::<usize>().ok().filter(|&i| i < devices.len());
+    let partial = {
+        let matching: Vec<usize> = names.iter().enumerate()
+            .filter(|(_, name)| name.to_lowercase().contains(&selector.to_lowercase()))
+            .map(|(i, _)| i)
+            .collect();
+        if matching.len() == 1 { Some(matching[0]) } else { None }
+    };
+
+    match exact.or(index).or(partial) {
+        Some(i) => Ok(devices.into_iter().nth(i).unwrap()),
+        None => {
+            let available: Vec<String> = names.iter().enumerate()
+                .map(|(i, name)| format!("  {}: {}", i, name))
+                .collect();
+            bail!("No {} device matching {:?}, available devices:\n{}", kind, selector, available.join("\n"))
+        }
+    }
+}
+
+// Interleaved multichannel samples to mono
+pub fn downmix_samples(data: &[f32], channels: usize, mode: Downmix) -> impl Iterator<Item = f32> + '_ {
+    data.chunks_exact(channels.max(1)).map(move |frame| {
+        match mode {
+            Downmix::Average => frame.iter().sum::<f32>() / frame.len() as f32,
+            Downmix::Channel(channel) => frame[channel.min(frame.len() - 1)],
+        }
+    })
+}
+
+// Converts mono device audio to SAMPLE_RATE, passing it through untouched if the
+// device already runs at that rate
+pub struct InputResampler {
+    resampler: Option<FftFixedIn<f32>>,
+    pending: Vec<f32>,
+}
+impl InputResampler {
+    pub fn new(device_rate: u32) -> Result<Self> {
+        let resampler = if device_rate == SAMPLE_RATE {
+            None
+        } else {
+            // ~10ms chunks to keep latency down
+            Some(FftFixedIn::new(device_rate as usize, SAMPLE_RATE as usize, device_rate as usize / 100, 2, 1)?)
+        };
+
+        Ok(InputResampler { resampler, pending: Vec::new() })
+    }
+    pub fn process(&mut self, samples: impl Iterator<Item = f32>) -> Vec<f32> {
+        let Some(resampler) = &mut self.resampler else {
+            return samples.collect();
+        };
+
+        self.pending.extend(samples);
+        let mut output = Vec::new();
+        while self.pending.len() >= resampler.input_frames_next() {
+            let chunk: Vec<f32> = self.pending.drain(..resampler.input_frames_next()).collect();
+            match resampler.process(&[chunk], None) {
+                Ok(mut resampled) => output.append(&mut resampled[0]),
+                Err(err) => eprintln!("Resampling failed: {:?}", err),
+            }
+        }
+        output
+    }
+}
+
+// Linear interpolation, good enough for logged clips and the VAD
+pub fn resample_linear(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let ratio = from as f64 / to as f64;
+    let length = (samples.len() as f64 / ratio).floor() as usize;
+    (0..length).map(|i| {
+        let position = i as f64 * ratio;
+        let index = position as usize;
+        let fraction = (position - index as f64) as f32;
+        let next = samples.get(index + 1).unwrap_or(&samples[index]);
+        samples[index] * (1. - fraction) + next * fraction
+    }).collect()
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::chat::Entry;
+use crate::config::CacheConfig;
+use crate::transcript::Transcript;
+
+// Answers to prompts heard before, keyed by the prompt and what's sent with
+// it (the chat's seed, the entries before it and remembered facts), for
+// cache.ttl_minutes
+pub struct ResponseCache {
+    config: CacheConfig,
+    responses: Mutex<HashMap<u64, (Instant, String)>>,
+}
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        ResponseCache { config: config.clone(), responses: Mutex::new(HashMap::new()) }
+    }
+
+    // None when caching is off or the answer could change from one minute to
+    // the next ("what time is it")
+    pub fn key(&self, transcript: &Transcript, seed: &[Entry], context: &[Entry], notes: &[String]) -> Option<u64> {
+        if !self.config.enabled {
+            return None;
+        }
+        let words: Vec<&str> = transcript.normalized()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        if words.iter().any(|word| self.config.bypass_words.iter().any(|bypass| bypass.eq_ignore_ascii_case(word))) {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        words.hash(&mut hasher);
+        for entry in seed.iter().chain(context) {
+            entry.content().hash(&mut hasher);
+        }
+        notes.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    pub fn get(&self, key: u64) -> Option<String> {
+        let ttl = Duration::from_secs(self.config.ttl_minutes * 60);
+        let mut responses = self.responses.lock().unwrap();
+        responses.retain(|_, (cached, _)| cached.elapsed() < ttl);
+        responses.get(&key).map(|(_, response)| response.clone())
+    }
+    pub fn insert(&self, key: u64, response: &str) {
+        self.responses.lock().unwrap().insert(key, (Instant::now(), response.to_string()));
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use base64::Engine;
+
+use crate::config::CameraConfig;
+
+// A JPEG snapshot from the webcam, as a data: URL
+pub fn snapshot(config: &CameraConfig) -> Result<String> {
+    let jpeg = capture(config)?;
+    Ok(format!("data:image/jpeg;base64,{}", base64::engine::general_purpose::STANDARD.encode(jpeg)))
+}
+
+// Asks for MJPEG, which nearly every webcam does, so the frames are JPEGs
+// already
+#[cfg(feature = "camera")]
+fn capture(config: &CameraConfig) -> Result<Vec<u8>> {
+    use anyhow::{Context, bail};
+    use v4l::buffer::Type;
+    use v4l::io::mmap::Stream;
+    use v4l::io::traits::CaptureStream;
+    use v4l::video::Capture;
+    use v4l::{Device, FourCC};
+
+    let device = Device::with_path(&config.device).with_context(|| format!("Failed to open camera {:?}", config.device))?;
+    let mut format = device.format()?;
+    format.width = config.width;
+    format.height = config.height;
+    format.fourcc = FourCC::new(b"MJPG");
+    let format = device.set_format(&format)?;
+    if format.fourcc != FourCC::new(b"MJPG") {
+        bail!("Camera {:?} can't send MJPEG (only {})", config.device, format.fourcc);
+    }
+
+    let mut stream = Stream::with_buffers(&device, Type::VideoCapture, 4)?;
+    // The first frames are dark while it adjusts
+    for _ in 0..config.warmup_frames {
+        stream.next()?;
+    }
+    let (frame, metadata) = stream.next()?;
+    Ok(frame[..metadata.bytesused as usize].to_vec())
+}
+#[cfg(not(feature = "camera"))]
+fn capture(_config: &CameraConfig) -> Result<Vec<u8>> {
+    anyhow::bail!("Built without camera support, rebuild with --features camera")
+}
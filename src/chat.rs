@@ -1,43 +1,38 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use serde_json::value::Value;
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
+use tokio::sync::mpsc::UnboundedSender;
+
+use tiktoken_rs::{CoreBPE, cl100k_base_singleton, o200k_base_singleton};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
 
-const KEY: &str = include_str!("secret.key");
+use crate::llm::{LanguageModel, RequestOptions, Usage};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "role", content = "content")]
-#[serde(rename_all = "lowercase")]
+#[serde(into = "Message", try_from = "Message")]
 pub enum Entry {
     System(String),
     Assistant(String),
     User(String),
+    // With a picture for vision models, as a data: URL
+    UserImage { content: String, image: String },
+    // The assistant asking for tools to be run instead of answering
+    ToolCalls(Vec<ToolCall>),
+    // What one of them returned, name being the tool's
+    Tool { call_id: String, name: String, content: String },
 }
 impl Entry {
     pub fn content(&self) -> &str {
         match &self {
-            Self::System(s) | Self::Assistant(s) | Self::User(s) => {
+            Self::System(s) | Self::Assistant(s) | Self::User(s) | Self::UserImage { content: s, .. } | Self::Tool { content: s, .. } => {
                 s
             }
+            Self::ToolCalls(_) => "",
         }
     }
-    pub fn as_table(&self) -> Option<Vec<Vec<String>>> {
-        let mut content = self.content().to_string();
-        content = content.split_off(content.find("|")?);
-        content.truncate(content.rfind("|")? + 1);
-
-        let rows: Vec<Vec<_>> =
-            content.split("\n").enumerate()
-            .filter_map(|(i, row)| {
-                if i != 1 {
-                    Some(row[1..(row.len()-1)].split("|")
-                         .map(|e| e.trim().to_string()).collect())
-                } else {
-                    None
-                }
-            }).collect();
-
-        Some(rows)
-    }
     pub fn as_json(&self) -> Option<Value> {
         let mut content = self.content().to_string();
         let array = content.find("[").unwrap_or(usize::MAX) < content.find("{").unwrap_or(usize::MAX);
@@ -58,61 +53,648 @@ impl Entry {
             Some(json)
         }
     }
+    // Fenced (```) code blocks, with their language tag if they have one
+    pub fn as_code_blocks(&self) -> Vec<(Option<String>, String)> {
+        let mut blocks = Vec::new();
+        let mut rest = self.content();
+        while let Some(start) = rest.find("```") {
+            let block = &rest[start + 3..];
+            let Some(newline) = block.find('\n') else { break };
+            let language = block[..newline].trim();
+            let code = &block[newline + 1..];
+            let Some(end) = code.find("```") else { break };
+
+            blocks.push(((!language.is_empty()).then(|| language.to_lowercase()), code[..end].trim_end().to_string()));
+            rest = &code[end + 3..];
+        }
+        blocks
+    }
+    // The items of a bulleted ("- ", "* ", "• ") or numbered ("1. ", "1) ")
+    // list, without their markers
+    pub fn as_list(&self) -> Option<Vec<String>> {
+        let items: Vec<String> = self.content().lines()
+            .filter_map(|line| {
+                let line = line.trim();
+                let item = ["- ", "* ", "• "].iter().find_map(|bullet| line.strip_prefix(bullet)).or_else(|| {
+                    let rest = line.trim_start_matches(|c: char| c.is_ascii_digit());
+                    (rest.len() < line.len()).then(|| rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") "))).flatten()
+                })?;
+                Some(item.trim().to_string()).filter(|item| !item.is_empty())
+            })
+            .collect();
+        (!items.is_empty()).then_some(items)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub function: FunctionCall,
+}
+impl Default for ToolCall {
+    fn default() -> Self {
+        ToolCall { id: String::new(), ty: "function".to_string(), function: FunctionCall::default() }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    // A JSON object, as a string
+    pub arguments: String,
+}
+
+// An entry as the OpenAI API has it
+#[derive(Serialize, Deserialize)]
+struct Message {
+    role: String,
+    // A string, or text and image parts
+    #[serde(default)]
+    content: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+impl From<Entry> for Message {
+    fn from(entry: Entry) -> Self {
+        let message = |role: &str, content: String| Message {
+            role: role.to_string(),
+            content: Some(Value::String(content)),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        };
+        match entry {
+            Entry::System(content) => message("system", content),
+            Entry::Assistant(content) => message("assistant", content),
+            Entry::User(content) => message("user", content),
+            Entry::UserImage { content, image } => Message {
+                content: Some(serde_json::json!([
+                    { "type": "text", "text": content },
+                    { "type": "image_url", "image_url": { "url": image } },
+                ])),
+                ..message("user", String::new())
+            },
+            Entry::ToolCalls(calls) => Message { tool_calls: Some(calls), content: None, ..message("assistant", String::new()) },
+            Entry::Tool { call_id, name, content } => Message { tool_call_id: Some(call_id), name: Some(name), ..message("tool", content) },
+        }
+    }
+}
+impl TryFrom<Message> for Entry {
+    type Error = anyhow::Error;
+    fn try_from(message: Message) -> Result<Self> {
+        let (content, image) = match message.content {
+            Some(Value::Array(parts)) => (
+                parts.iter().filter_map(|part| part["text"].as_str()).collect::<String>(),
+                parts.iter().find_map(|part| part.pointer("/image_url/url").and_then(Value::as_str)).map(str::to_string),
+            ),
+            Some(Value::String(content)) => (content, None),
+            _ => (String::new(), None),
+        };
+        Ok(match message.role.as_str() {
+            "system" => Entry::System(content),
+            "user" => match image {
+                Some(image) => Entry::UserImage { content, image },
+                None => Entry::User(content),
+            },
+            "assistant" => match message.tool_calls {
+                Some(calls) if !calls.is_empty() => Entry::ToolCalls(calls),
+                _ => Entry::Assistant(content),
+            },
+            "tool" => Entry::Tool {
+                call_id: message.tool_call_id.context("Tool result without a tool_call_id")?,
+                // Chats saved before names were kept don't have one
+                name: message.name.unwrap_or_default(),
+                content,
+            },
+            role => bail!("Unknown role {:?}", role),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Api {
+    #[default]
+    OpenAi,
+    // Ollama's own /api/chat, url is then the server, e.g. "http://localhost:11434"
+    Ollama,
+    // Azure OpenAI, url is then the resource (e.g.
+    // "https://my-resource.openai.azure.com") and model the deployment name
+    Azure,
+    // Anthropic's Messages API, url defaults to "https://api.anthropic.com"
+    Anthropic,
+    // llama.cpp in-process with the llama feature, model is then the path to
+    // a GGUF file and url is unused
+    LlamaCpp,
+}
+
+// How replies are constrained to the assistant's JSON, for backends that can
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    // Left to the prompt
+    Text,
+    // Any JSON object (OpenAI's json_object, Ollama's "json")
+    #[default]
+    Json,
+    // The reply schema itself, OpenAI structured outputs (gpt-4o and later)
+    // or an Ollama format schema
+    Schema,
+}
+
+const AZURE_API_VERSION: &str = "2024-06-01";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Backend {
+    pub name: String,
+    #[serde(default)]
+    pub api: Api,
+    // Anything that speaks the OpenAI API: either the full chat completions
+    // endpoint or just the base, e.g. "http://localhost:11434/v1" for Ollama
+    // or "http://localhost:1234/v1" for LM Studio
+    #[serde(default)]
+    pub url: String,
+    pub model: String,
+    // Otherwise taken from $<NAME>_API_KEY (e.g. $OPENAI_API_KEY) or, with the
+    // keyring feature, the OS keyring. Local servers usually don't need one
+    pub api_key: Option<String>,
+    // Left to the server when unset
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    // Ollama only, how long it keeps the model loaded after a request, e.g.
+    // "30m" or "-1" for forever
+    #[serde(default)]
+    pub keep_alive: Option<String>,
+    // llama.cpp only, in tokens, 4096 when unset
+    #[serde(default)]
+    pub context_size: Option<u32>,
+    // USD per million tokens, for usage tracking. Known models have a
+    // built-in price
+    #[serde(default)]
+    pub prompt_price: Option<f64>,
+    #[serde(default)]
+    pub completion_price: Option<f64>,
+    // Azure only, the api-version query parameter
+    #[serde(default)]
+    pub api_version: Option<String>,
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+}
+impl Backend {
+    pub fn openai() -> Self {
+        Backend {
+            name: "openai".to_string(),
+            api: Api::OpenAi,
+            url: "https://api.openai.com/v1/chat/completions".to_string(),
+            model: "gpt-3.5-turbo".to_string(),
+            api_key: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            keep_alive: None,
+            context_size: None,
+            prompt_price: None,
+            completion_price: None,
+            api_version: None,
+            response_format: ResponseFormat::Json,
+        }
+    }
+    pub fn completions_url(&self) -> String {
+        let url = self.url.trim_end_matches('/');
+        if self.api == Api::Azure {
+            let api_version = self.api_version.as_deref().unwrap_or(AZURE_API_VERSION);
+            return match url.contains("/deployments/") {
+                true => format!("{}?api-version={}", url, api_version),
+                false => format!("{}/openai/deployments/{}/chat/completions?api-version={}", url, self.model, api_version),
+            };
+        }
+        match url.ends_with("/chat/completions") {
+            true => url.to_string(),
+            false => format!("{}/chat/completions", url),
+        }
+    }
+    // For embedding models, the url can be the embeddings endpoint, the base
+    // or (for OpenAI) the chat completions endpoint
+    pub fn embeddings_url(&self) -> String {
+        let url = self.url.trim_end_matches('/');
+        match self.api {
+            Api::Azure => {
+                let api_version = self.api_version.as_deref().unwrap_or(AZURE_API_VERSION);
+                format!("{}/openai/deployments/{}/embeddings?api-version={}", url, self.model, api_version)
+            },
+            Api::Ollama => format!("{}/api/embed", url),
+            _ if url.ends_with("/embeddings") => url.to_string(),
+            _ => format!("{}/embeddings", url.trim_end_matches("/chat/completions")),
+        }
+    }
+    pub fn api_key_var(&self) -> String {
+        let name: String = self.name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        format!("{}_API_KEY", name)
+    }
+    // Whether the server is one that won't answer without a key
+    pub fn needs_api_key(&self) -> bool {
+        match self.api {
+            Api::OpenAi => self.url.contains("api.openai.com"),
+            Api::Azure => true,
+            Api::Anthropic => self.url.is_empty() || self.url.contains("api.anthropic.com"),
+            Api::Ollama | Api::LlamaCpp => false,
+        }
+    }
+    // Fills in api_key from the environment or keyring when it isn't set
+    pub fn resolve_api_key(&mut self) {
+        if self.api_key.is_some() {
+            return;
+        }
+        self.api_key = std::env::var(self.api_key_var()).ok().filter(|key| !key.trim().is_empty());
+        #[cfg(feature = "keyring")]
+        if self.api_key.is_none() {
+            self.api_key = keyring::Entry::new(KEYRING_SERVICE, &self.name)
+                .and_then(|entry| entry.get_password())
+                .ok();
+        }
+    }
+}
+
+const SUMMARY_PROMPT: &str = "Summarize this conversation between a user and a voice assistant in a few sentences. \
+    Keep names, facts, preferences and anything still being worked on, leave out small talk. \
+    Reply with only the summary, as plain text.";
+
+// Roughly what OpenAI charges for a camera snapshot at high detail
+const IMAGE_TOKENS: usize = 765;
+
+// The model's tokenizer. Models tiktoken doesn't know (local ones) get
+// cl100k, which is close enough for budgeting
+fn tokenizer(model: &str) -> &'static CoreBPE {
+    match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+        _ => cl100k_base_singleton(),
+    }
+}
+fn entry_tokens(bpe: &CoreBPE, entry: &Entry) -> usize {
+    let count = |text: &str| bpe.encode_with_special_tokens(text).len();
+    match entry {
+        Entry::ToolCalls(calls) => calls.iter().map(|call| count(&call.function.name) + count(&call.function.arguments)).sum(),
+        Entry::UserImage { content, .. } => count(content) + IMAGE_TOKENS,
+        entry => count(entry.content()),
+    }
+}
+fn tools_tokens(bpe: &CoreBPE, tools: &[Value]) -> usize {
+    tools.iter().map(|tool| bpe.encode_with_special_tokens(&tool.to_string()).len()).sum()
 }
 
+// What's known about an entry besides its content. Entries from chats saved
+// before this was kept have no creation time
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EntryMeta {
+    pub created: Option<DateTime<Utc>>,
+    // Who said it, for user entries when the speaker was recognized
+    pub speaker: Option<String>,
+    // Completion tokens for replies, otherwise its size counted locally
+    pub tokens: u64,
+}
+
+#[cfg(feature = "keyring")]
+pub const KEYRING_SERVICE: &str = "assistant";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Chat {
-    model: &'static str,
     messages: Vec<Entry>,
+    // One per message, kept alongside rather than in Entry so the messages
+    // can be sent as they are
+    #[serde(default)]
+    meta: Vec<EntryMeta>,
 
-    #[serde(skip_serializing)]
+    #[serde(default)]
     tokens: u64,
+    // Leading entries (system prompt and example turns) that are never dropped
+    #[serde(default)]
+    seed_len: usize,
+    // Cap on entries after the seed
+    #[serde(skip)]
+    max_entries: Option<usize>,
+    // Cap on the tokens sent with each request
+    #[serde(skip)]
+    token_budget: Option<usize>,
+    // Where the turn being answered starts, nothing from it is dropped to
+    // make room
+    #[serde(skip)]
+    turn_start: usize,
+    // Whose tokenizer to count them with
+    #[serde(skip)]
+    token_model: String,
+    // Tool schemas and the reply schema sent with every request
+    #[serde(skip)]
+    options: RequestOptions,
+    // Who's talking, recorded with their entries
+    #[serde(skip)]
+    speaker: Option<String>,
 }
 impl Chat {
     pub fn new() -> Self {
         Chat {
-            model: "gpt-3.5-turbo",
             messages: Vec::new(),
+            meta: Vec::new(),
             tokens: 0,
+            seed_len: 0,
+            max_entries: None,
+            token_budget: None,
+            turn_start: 0,
+            token_model: String::new(),
+            options: RequestOptions::default(),
+            speaker: None,
+        }
+    }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read chat {:?}", path))?;
+        Self::parse(&contents).with_context(|| format!("Failed to parse chat {:?}", path))
+    }
+    pub fn parse(json: &str) -> Result<Self> {
+        let mut chat: Chat = serde_json::from_str(json)?;
+        if chat.meta.len() != chat.messages.len() {
+            let bpe = tokenizer(&chat.token_model);
+            chat.meta = chat.messages.iter()
+                .map(|entry| EntryMeta { tokens: entry_tokens(bpe, entry) as u64, ..Default::default() })
+                .collect();
+        }
+        Ok(chat)
+    }
+    // Written next to it first, so a crash mid-write doesn't lose the chat
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
         }
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&temp, path).with_context(|| format!("Failed to save chat {:?}", path))
+    }
+    pub fn set_tools(&mut self, tools: Vec<Value>) {
+        self.options.tools = tools;
+    }
+    pub fn set_response_schema(&mut self, schema: Option<Value>) {
+        self.options.schema = schema;
+    }
+    pub fn set_max_entries(&mut self, max_entries: Option<usize>) {
+        self.max_entries = max_entries;
+        self.enforce_max_entries();
+    }
+    pub fn set_token_model(&mut self, model: impl AsRef<str>) {
+        self.token_model = model.as_ref().to_string();
+    }
+    pub fn set_speaker(&mut self, speaker: Option<&str>) {
+        self.speaker = speaker.map(str::to_string);
+    }
+    pub fn set_token_budget(&mut self, token_budget: Option<usize>) {
+        self.token_budget = token_budget;
+    }
+    // Mark everything pushed so far as seed entries
+    pub fn mark_seed(&mut self) {
+        self.seed_len = self.messages.len();
+    }
+    // Everything pushed from here on is part of the turn being answered
+    pub fn start_turn(&mut self) {
+        self.turn_start = self.messages.len();
     }
     pub fn push_entry(&mut self, entry: Entry) {
+        let tokens = entry_tokens(tokenizer(&self.token_model), &entry) as u64;
+        self.push_entry_with_tokens(entry, tokens);
+    }
+    fn push_entry_with_tokens(&mut self, entry: Entry, tokens: u64) {
+        let speaker = match entry {
+            Entry::User(_) | Entry::UserImage { .. } => self.speaker.clone(),
+            _ => None,
+        };
         self.messages.push(entry);
+        self.meta.push(EntryMeta { created: Some(Utc::now()), speaker, tokens });
+        self.enforce_max_entries();
     }
     pub fn push_system(&mut self, message: impl AsRef<str>) {
-        self.messages.push(Entry::System(message.as_ref().to_string()));
+        self.push_entry(Entry::System(message.as_ref().to_string()));
     }
     pub fn push_assistant(&mut self, message: impl AsRef<str>) {
-        self.messages.push(Entry::Assistant(message.as_ref().to_string()));
+        self.push_entry(Entry::Assistant(message.as_ref().to_string()));
     }
     pub fn push_user(&mut self, message: impl AsRef<str>) {
-        self.messages.push(Entry::User(message.as_ref().to_string()));
+        self.push_entry(Entry::User(message.as_ref().to_string()));
+    }
+    pub fn push_user_image(&mut self, message: impl AsRef<str>, image: impl AsRef<str>) {
+        self.push_entry(Entry::UserImage { content: message.as_ref().to_string(), image: image.as_ref().to_string() });
+    }
+    // Pictures are only needed for the turn they're asked about, after that
+    // they'd be sent (and paid for) again with every request
+    pub fn drop_images(&mut self) {
+        let bpe = tokenizer(&self.token_model);
+        for (entry, meta) in self.messages.iter_mut().zip(&mut self.meta) {
+            if let Entry::UserImage { content, .. } = entry {
+                *entry = Entry::User(std::mem::take(content));
+                meta.tokens = entry_tokens(bpe, entry) as u64;
+            }
+        }
+    }
+    pub fn push_tool_result(&mut self, call: &ToolCall, content: impl AsRef<str>) {
+        self.push_entry(Entry::Tool { call_id: call.id.clone(), name: call.function.name.clone(), content: content.as_ref().to_string() });
+    }
+    pub fn push_completion(&mut self, completion: Entry, usage: Usage) {
+        self.tokens += usage.total();
+        // Local servers don't always report usage
+        match usage.completion_tokens {
+            0 => self.push_entry(completion),
+            tokens => self.push_entry_with_tokens(completion, tokens),
+        }
+    }
+    pub fn pop(&mut self) -> Option<Entry> {
+        self.meta.pop();
+        let entry = self.messages.pop();
+        self.turn_start = self.turn_start.min(self.messages.len());
+        entry
+    }
+    // Keep only the first len entries, the seed included
+    pub fn truncate_to(&mut self, len: usize) {
+        self.messages.truncate(len);
+        self.meta.truncate(len);
+        self.seed_len = self.seed_len.min(len);
+        self.turn_start = self.turn_start.min(len);
+    }
+    // Keep only the entries keep says to, the seed included
+    pub fn retain(&mut self, mut keep: impl FnMut(&Entry, &EntryMeta) -> bool) {
+        let kept: Vec<bool> = self.messages.iter().zip(&self.meta).map(|(entry, meta)| keep(entry, meta)).collect();
+        self.seed_len = kept.iter().take(self.seed_len).filter(|kept| **kept).count();
+        self.turn_start = kept.iter().take(self.turn_start).filter(|kept| **kept).count();
+        let mut i = 0;
+        self.messages.retain(|_| { i += 1; kept[i - 1] });
+        let mut i = 0;
+        self.meta.retain(|_| { i += 1; kept[i - 1] });
+    }
+    // Swap in a new system prompt (the first entry), or add one if there
+    // isn't one
+    pub fn replace_system(&mut self, message: impl AsRef<str>) {
+        let entry = Entry::System(message.as_ref().to_string());
+        let meta = EntryMeta { created: Some(Utc::now()), speaker: None, tokens: entry_tokens(tokenizer(&self.token_model), &entry) as u64 };
+        if let Some(Entry::System(_)) = self.messages.first() {
+            self.messages[0] = entry;
+            self.meta[0] = meta;
+        } else {
+            self.messages.insert(0, entry);
+            self.meta.insert(0, meta);
+            self.seed_len += 1;
+            self.turn_start += 1;
+        }
     }
     pub fn last(&self) -> Option<&Entry> {
         self.messages.last()
     }
+    // The system prompt and example turns
+    pub fn seed(&self) -> &[Entry] {
+        &self.messages[..self.seed_len.min(self.messages.len())]
+    }
+    // The last count entries after the seed
+    pub fn recent(&self, count: usize) -> &[Entry] {
+        let history = &self.messages[self.seed_len.min(self.messages.len())..];
+        &history[history.len().saturating_sub(count)..]
+    }
+    // What the next request will cost in prompt tokens
+    pub fn request_tokens(&self) -> usize {
+        // Entries were counted when pushed. Every message has a few tokens of
+        // framing, and the reply is primed with 3
+        let messages: usize = self.meta.iter().map(|meta| 3 + meta.tokens as usize).sum();
+        messages + tools_tokens(tokenizer(&self.token_model), &self.options.tools) + 3
+    }
+    // Forget everything after the seed
+    pub fn reset(&mut self) {
+        self.truncate_to(self.seed_len);
+    }
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+    // Entries after the seed
+    pub fn history_len(&self) -> usize {
+        self.messages.len().saturating_sub(self.seed_len)
+    }
+
+    // Drop the oldest non-seed, non-system entries until under the cap
+    fn enforce_max_entries(&mut self) {
+        let Some(max_entries) = self.max_entries else { return };
+
+        while self.messages.len().saturating_sub(self.seed_len) > max_entries {
+            if !self.drop_oldest() {
+                break;
+            }
+        }
+    }
+    // Same for the token budget, run before each request
+    pub fn fit_token_budget(&mut self) {
+        let Some(token_budget) = self.token_budget else { return };
 
-    pub async fn complete(&mut self) -> Result<&mut Self> {
-        let client = reqwest::Client::new();
-        let res = client.post("https://api.openai.com/v1/chat/completions")
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .bearer_auth(KEY)
-            .json(&self)
-            .send()
-            .await?;
-        let mut val = res.json::<Value>().await?;
-
-        let completion: Entry = serde_json::from_value(
-            val.get_mut("choices").context("No choices")?.take()
-                .get_mut(0).context("0-length choices")?.take()
-                .get_mut("message").context("No message")?.take()
-        )?;
-        let tokens_used = val.get("usage").context("No usage")?
-            .get("total_tokens").context("No usage")?
-            .as_u64().context("Not u64")?;
-
-        self.tokens += tokens_used;
-        self.push_entry(completion);
+        while self.request_tokens() > token_budget {
+            if !self.drop_oldest() {
+                break;
+            }
+        }
+    }
+    // Never from the turn being answered, or at least its newest entry
+    fn drop_oldest(&mut self) -> bool {
+        let newest = self.messages.len().saturating_sub(1);
+        let keep_from = match self.turn_start >= self.seed_len {
+            true => self.turn_start.min(newest),
+            false => newest,
+        };
+        let oldest = self.messages.iter().enumerate()
+            .skip(self.seed_len)
+            .find(|(i, entry)| *i < keep_from && !matches!(entry, Entry::System(_)))
+            .map(|(i, _)| i);
+        let Some(i) = oldest else { return false };
+        // Tool results can't outlive the call they answer
+        let mut removed = 1;
+        self.meta.remove(i);
+        if let Entry::ToolCalls(_) = self.messages.remove(i) {
+            while matches!(self.messages.get(i), Some(Entry::Tool { .. })) {
+                self.messages.remove(i);
+                self.meta.remove(i);
+                removed += 1;
+            }
+        }
+        if self.turn_start > i {
+            self.turn_start = self.turn_start.saturating_sub(removed).max(i);
+        }
+        true
+    }
+
+    // Replace all but the newest keep entries after the seed with a summary
+    // of them, earlier summaries included
+    pub async fn summarize(&mut self, model: &dyn LanguageModel, keep: usize) -> Result<()> {
+        let mut end = self.messages.len().saturating_sub(keep).max(self.seed_len);
+        // Tool results stay with their call
+        while matches!(self.messages.get(end), Some(Entry::Tool { .. })) {
+            end += 1;
+        }
+        if end <= self.seed_len {
+            return Ok(());
+        }
+
+        let transcript = self.messages[self.seed_len..end].iter()
+            .zip(&self.meta[self.seed_len..end])
+            .filter_map(|(entry, meta)| match entry {
+                Entry::System(content) => Some(format!("Note: {}", content)),
+                Entry::User(content) | Entry::UserImage { content, .. } => match &meta.speaker {
+                    Some(speaker) => Some(format!("User ({}): {}", speaker, content)),
+                    None => Some(format!("User: {}", content)),
+                },
+                Entry::Assistant(content) => Some(format!("Assistant: {}", content)),
+                Entry::Tool { name, content, .. } => Some(format!("Result of {}: {}", name, content)),
+                Entry::ToolCalls(_) => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let request = [
+            Entry::System(SUMMARY_PROMPT.to_string()),
+            Entry::User(transcript),
+        ];
+        let (summary, usage) = model.request(&request, &RequestOptions::default(), None).await?;
+        self.tokens += usage.total();
+
+        let summary = Entry::System(format!("Summary of the conversation so far: {}", summary.content().trim()));
+        let tokens = entry_tokens(tokenizer(&self.token_model), &summary) as u64;
+        if self.turn_start >= end {
+            self.turn_start -= end - self.seed_len - 1;
+        }
+        self.messages.splice(self.seed_len..end, [summary]);
+        self.meta.splice(self.seed_len..end, [EntryMeta { created: Some(Utc::now()), speaker: None, tokens }]);
+        Ok(())
+    }
+
+    // Request a completion without adding it to the history
+    pub async fn request(&self, model: &dyn LanguageModel) -> Result<(Entry, Usage)> {
+        model.request(&self.messages, &self.options, None).await
+    }
+    pub async fn complete(&mut self, model: &dyn LanguageModel) -> Result<&mut Self> {
+        let (completion, usage) = self.request(model).await?;
+        self.push_completion(completion, usage);
+
+        Ok(self)
+    }
+
+    // Like request, but sends each piece of the completion to deltas as it's
+    // generated
+    pub async fn request_stream(&self, model: &dyn LanguageModel, deltas: UnboundedSender<String>) -> Result<(Entry, Usage)> {
+        model.request(&self.messages, &self.options, Some(deltas)).await
+    }
+    pub async fn complete_stream(&mut self, model: &dyn LanguageModel, deltas: UnboundedSender<String>) -> Result<&mut Self> {
+        let (completion, usage) = self.request_stream(model, deltas).await?;
+        self.push_completion(completion, usage);
 
         Ok(self)
     }
@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+
+use crate::config::Config;
+use crate::wakeword;
+
+#[derive(Parser, Debug)]
+#[command(about = "Voice assistant using whisper, rustpotter and ChatGPT")]
+pub struct Args {
+    /// Config file, created with defaults if it doesn't exist
+    #[arg(short, long, default_value = "config.toml")]
+    pub config: PathBuf,
+    /// Whisper ggml model, overrides whisper.model_path
+    #[arg(short, long)]
+    pub model: Option<String>,
+    /// Directory of wakeword sample clips (.wav)
+    #[arg(short, long)]
+    pub wakeword_dir: Option<PathBuf>,
+    /// Input device name or index, overrides audio.input_device
+    #[arg(long)]
+    pub input_device: Option<String>,
+    /// Output device name or index, overrides audio.output_device
+    #[arg(long)]
+    pub output_device: Option<String>,
+    /// Read input from a WAV file instead of a device
+    #[arg(long)]
+    pub input_file: Option<String>,
+    /// Receive input over UDP on this address instead of a device
+    #[arg(long)]
+    pub input_udp: Option<String>,
+    /// Run as a satellite, streaming audio to the assistant server at this address
+    #[arg(long, conflicts_with = "server")]
+    pub satellite: Option<String>,
+    /// Run as a server for satellites, listening on this address
+    #[arg(long)]
+    pub server: Option<String>,
+    /// More output, repeat for more
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Build a wakeword model (.rpw) from a directory of sample clips
+    TrainWakeword {
+        /// Directory of sample clips (.wav)
+        dir: PathBuf,
+        /// Wakeword name, defaults to wakeword.name
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Where to save the model, defaults to <name>.rpw
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Record sample clips of the wakeword, prompting for each one
+    RecordWakeword {
+        /// Directory to save the clips (.wav) in
+        dir: PathBuf,
+        /// How many clips to record
+        #[arg(short, long, default_value_t = 5)]
+        count: usize,
+    },
+    /// Record someone reading out a few sentences, so they can be told apart
+    EnrollSpeaker {
+        /// Their name, as the assistant should know them
+        name: String,
+        /// Directory to save the clips in, defaults to speakers/<name>
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+        /// How many clips to record
+        #[arg(short, long, default_value_t = 6)]
+        count: usize,
+    },
+    /// Save an LLM API key in the OS keyring (needs the keyring feature)
+    StoreApiKey {
+        /// Backend name, defaults to llm.cloud.name
+        #[arg(short, long)]
+        backend: Option<String>,
+    },
+    /// Save a chat (its saved history, or a new one) to a JSON file, system
+    /// prompts and all
+    ExportChat {
+        /// Where to save it
+        path: PathBuf,
+        /// Whose chat, defaults to wakeword.name
+        #[arg(short, long)]
+        wakeword: Option<String>,
+        /// The speaker's own chat rather than the shared one
+        #[arg(short, long)]
+        speaker: Option<String>,
+        /// A satellite's chat, when running as a server
+        #[arg(long)]
+        satellite: Option<String>,
+    },
+    /// Load a chat saved with export-chat, replacing the saved history (needs
+    /// chat.history_dir)
+    ImportChat {
+        /// The exported chat
+        path: PathBuf,
+        /// Whose chat, defaults to wakeword.name
+        #[arg(short, long)]
+        wakeword: Option<String>,
+        /// The speaker's own chat rather than the shared one
+        #[arg(short, long)]
+        speaker: Option<String>,
+        /// A satellite's chat, when running as a server
+        #[arg(long)]
+        satellite: Option<String>,
+    },
+    /// Print wakeword scores, including near-misses, and suggest a threshold
+    TuneWakeword {
+        /// How many times to say the wakeword
+        #[arg(short, long, default_value_t = 5)]
+        count: usize,
+        /// How long to listen to background noise for false activations
+        #[arg(short, long, default_value_t = 30)]
+        background_seconds: u64,
+    },
+}
+impl Args {
+    // Command line flags take priority over the config file
+    pub fn apply(&self, config: &mut Config) -> Result<()> {
+        if let Some(model) = &self.model {
+            config.whisper.model_path = Some(model.clone());
+        }
+        if let Some(dir) = &self.wakeword_dir {
+            config.wakeword.clips = wakeword::wav_clips(dir)?;
+        }
+        if let Some(device) = &self.input_device {
+            config.audio.input_device = Some(device.clone());
+        }
+        if let Some(device) = &self.output_device {
+            config.audio.output_device = Some(device.clone());
+        }
+        if let Some(path) = &self.input_file {
+            config.audio.input_file = Some(path.clone());
+        }
+        if let Some(bind) = &self.input_udp {
+            let network = config.audio.network.get_or_insert_with(Default::default);
+            network.bind = bind.clone();
+        }
+        config.debug.verbosity = config.debug.verbosity.max(self.verbose);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::Cursor;
+use std::time::Duration;
+
+use anyhow::{Result, Context, anyhow, bail};
+use base64::Engine;
+use hound::{WavSpec, WavWriter};
+use serde_json::{Value, json};
+
+use crate::audio::SAMPLE_RATE;
+use crate::config::{CloudSttConfig, CloudSttProvider};
+use crate::transcribe::{Transcriber, Transcription};
+
+// Sends each command to a speech-to-text service as a 16-bit WAV
+pub struct CloudTranscriber {
+    config: CloudSttConfig,
+    api_key: String,
+}
+impl CloudTranscriber {
+    pub fn new(config: &CloudSttConfig) -> Result<Self> {
+        let api_key = config.api_key.clone()
+            .ok_or_else(|| anyhow!("stt.cloud.api_key isn't set"))?;
+        Ok(CloudTranscriber { config: config.clone(), api_key })
+    }
+
+    fn request(&self, wav: Vec<u8>) -> Result<String> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .build()?;
+        let config = &self.config;
+
+        let response = match config.provider {
+            CloudSttProvider::Deepgram => client
+                .post("https://api.deepgram.com/v1/listen")
+                .query(&[("model", config.model.as_deref().unwrap_or("nova-2")), ("language", &config.language), ("smart_format", "true")])
+                .header(reqwest::header::AUTHORIZATION, format!("Token {}", self.api_key))
+                .header(reqwest::header::CONTENT_TYPE, "audio/wav")
+                .body(wav),
+            CloudSttProvider::Google => client
+                .post("https://speech.googleapis.com/v1/speech:recognize")
+                .query(&[("key", &self.api_key)])
+                .json(&json!({
+                    "config": {
+                        "encoding": "LINEAR16",
+                        "sampleRateHertz": SAMPLE_RATE,
+                        "languageCode": config.language,
+                        "model": config.model.as_deref().unwrap_or("default"),
+                    },
+                    "audio": { "content": base64::engine::general_purpose::STANDARD.encode(wav) },
+                })),
+            CloudSttProvider::Azure => client
+                .post(format!(
+                    "https://{}.stt.speech.microsoft.com/speech/recognition/conversation/cognitiveservices/v1",
+                    config.region,
+                ))
+                .query(&[("language", &config.language)])
+                .header("Ocp-Apim-Subscription-Key", &self.api_key)
+                .header(reqwest::header::CONTENT_TYPE, format!("audio/wav; codecs=audio/pcm; samplerate={}", SAMPLE_RATE))
+                .body(wav),
+        }.send()?;
+
+        // Errors don't always come back as JSON
+        let status = response.status();
+        if !status.is_success() {
+            bail!("{}: {}", status, response.text().unwrap_or_default());
+        }
+        let body: Value = response.json().context("Invalid response")?;
+
+        // Each provider nests the transcript differently, and leaves it out
+        // when nothing was said
+        let transcript = match config.provider {
+            CloudSttProvider::Deepgram => body.pointer("/results/channels/0/alternatives/0/transcript")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            CloudSttProvider::Google => body["results"].as_array()
+                .map(|results| results.iter()
+                    .filter_map(|result| result.pointer("/alternatives/0/transcript").and_then(Value::as_str))
+                    .collect::<Vec<_>>()
+                    .join(" "))
+                .unwrap_or_default(),
+            CloudSttProvider::Azure => body["DisplayText"].as_str().unwrap_or_default().to_string(),
+        };
+        Ok(transcript)
+    }
+}
+impl Transcriber for CloudTranscriber {
+    fn transcribe(&mut self, samples: &[f32]) -> Result<Transcription> {
+        let wav = encode_wav(samples)?;
+        // Runs on the transcription worker, where blocking is fine
+        let transcript = self.request(wav)
+            .with_context(|| format!("{:?} speech-to-text failed", self.config.provider))?;
+        Ok(Transcription::text(vec![transcript]))
+    }
+}
+
+pub fn encode_wav(samples: &[f32]) -> Result<Vec<u8>> {
+    let mut cursor = Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(&mut cursor, WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    })?;
+    for sample in samples {
+        writer.write_sample((sample.clamp(-1., 1.) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(cursor.into_inner())
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Result, Context};
+
+use crate::chat::Chat;
+use crate::config::{Config, SpeakerProfile};
+use crate::store::Store;
+use crate::tools;
+use crate::transcript::Transcript;
+use crate::usage::today;
+
+const DEFAULT_PERSONA: &str = "You are a helpful audio-based assistant. You answer to 'computer' and 'peter', but your real name is 'Grenouille'.";
+
+// Who a chat is with: the wakeword (persona) and the speaker, if they're
+// known, which named context it's in (None for the main one), and the
+// satellite it's heard through when running as a server
+pub type ChatKey = (String, Option<String>, Option<String>, Option<String>);
+
+// Said to get back to the chat that isn't a named context
+const MAIN_CONTEXT: &str = "main";
+// The store setting the current named context is kept in
+const CONTEXT_SETTING: &str = "context";
+
+// Every chat, one per wakeword, speaker and named context. Named contexts are
+// switched between by voice, for everyone at once. Each satellite has its own
+pub struct ChatManager {
+    chats: HashMap<ChatKey, Chat>,
+    context: Option<String>,
+    satellite: Option<String>,
+    store: Option<Arc<Store>>,
+}
+impl ChatManager {
+    // The wakewords' chats are opened up front, the rest on demand. With a
+    // store, it's back in whichever context it was left in
+    pub fn new(config: &Config, wakewords: &[String], satellite: Option<&str>, store: Option<Arc<Store>>) -> Self {
+        let satellite = satellite.map(str::to_string);
+        let context = store.as_ref()
+            .and_then(|store| store.setting(&context_setting(satellite.as_deref())).map_err(|err| eprintln!("{:?}", err)).ok().flatten())
+            .filter(|name| config.chat.contexts.iter().any(|context| &context.name == name));
+        let chats = wakewords.iter()
+            .map(|wakeword| {
+                let key = (wakeword.clone(), None, context.clone(), satellite.clone());
+                let chat = open_chat(config, store.as_deref(), &key, None);
+                (key, chat)
+            })
+            .collect();
+        ChatManager { chats, context, satellite, store }
+    }
+
+    pub fn key(&self, wakeword: &str, speaker: Option<&SpeakerProfile>) -> ChatKey {
+        (wakeword.to_string(), speaker.map(|speaker| speaker.name.clone()), self.context.clone(), self.satellite.clone())
+    }
+    pub fn get(&mut self, config: &Config, key: &ChatKey, speaker: Option<&SpeakerProfile>) -> &mut Chat {
+        let store = self.store.as_deref();
+        self.chats.entry(key.clone()).or_insert_with(|| open_chat(config, store, key, speaker))
+    }
+    pub fn save(&self, config: &Config, key: &ChatKey) {
+        let Some(chat) = self.chats.get(key) else { return };
+        if let Err(err) = save_chat(config, self.store.as_deref(), key, chat) {
+            eprintln!("{:?}", err);
+        }
+    }
+
+    // Switches context when asked to ("switch to the cooking conversation"),
+    // returning its name
+    pub fn switch(&mut self, config: &Config, transcript: &Transcript) -> Option<String> {
+        let text = transcript.normalized();
+        if !config.chat.switch_phrases.iter().any(|phrase| text.contains(&phrase.to_lowercase())) {
+            return None;
+        }
+        let names = std::iter::once(MAIN_CONTEXT).chain(config.chat.contexts.iter().map(|context| context.name.as_str()));
+        let name = names.filter(|name| text.contains(&name.to_lowercase())).max_by_key(|name| name.len())?;
+        self.context = (name != MAIN_CONTEXT).then(|| name.to_string());
+        if let Some(store) = &self.store {
+            if let Err(err) = store.set_setting(&context_setting(self.satellite.as_deref()), self.context.as_deref()) {
+                eprintln!("Failed to save the context: {:?}", err);
+            }
+        }
+        Some(name.to_string())
+    }
+}
+
+// Satellites each stay in their own context
+fn context_setting(satellite: Option<&str>) -> String {
+    match satellite {
+        Some(satellite) => format!("{}-{}", CONTEXT_SETTING, satellite),
+        None => CONTEXT_SETTING.to_string(),
+    }
+}
+
+// Saves the chat to path, to be imported elsewhere
+pub fn export(config: &Config, store: Option<&Store>, key: &ChatKey, path: &Path) -> Result<()> {
+    let speaker = key.1.as_ref().and_then(|name| config.speaker.profiles.iter().find(|profile| &profile.name == name));
+    open_chat(config, store, key, speaker).save(path)?;
+    println!("Exported the chat to {:?}", path);
+    Ok(())
+}
+
+// Makes an exported chat the saved history, picked up on the next start
+pub fn import(config: &Config, store: Option<&Store>, key: &ChatKey, path: &Path) -> Result<()> {
+    let chat = Chat::load(path)?;
+    if store.is_none() && config.chat.history_dir.is_none() {
+        anyhow::bail!("Set store.path or chat.history_dir to import chats");
+    }
+    save_chat(config, store, key, &chat)?;
+    println!("Imported the chat as {:?}", chat_name(key));
+    Ok(())
+}
+
+// The persona's prompt, or the context's when it has its own
+fn context_prompt<'a>(config: &'a Config, key: &ChatKey) -> &'a str {
+    let context = key.2.as_ref().and_then(|name| config.chat.contexts.iter().find(|context| &context.name == name));
+    match context.and_then(|context| context.prompt.as_ref()) {
+        Some(prompt) => prompt,
+        None => persona_prompt(config, &key.0),
+    }
+}
+
+fn persona_prompt<'a>(config: &'a Config, wakeword: &str) -> &'a str {
+    config.personas.iter()
+        .find(|persona| persona.wakeword == wakeword)
+        .map_or(DEFAULT_PERSONA, |persona| persona.prompt.as_str())
+}
+
+// What a chat is saved as, e.g. "computer-alice-cooking", or
+// "kitchen-computer-alice-cooking" through the kitchen satellite
+fn chat_name(key: &ChatKey) -> String {
+    let name = [key.3.as_ref(), Some(&key.0), key.1.as_ref(), key.2.as_ref()].into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("-");
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+// Where a chat is saved, with chat.history_dir
+fn history_path(config: &Config, key: &ChatKey) -> Option<PathBuf> {
+    let dir = config.chat.history_dir.as_ref()?;
+    Some(Path::new(dir).join(format!("{}.json", chat_name(key))))
+}
+
+// To the store when there is one, otherwise chat.history_dir
+fn save_chat(config: &Config, store: Option<&Store>, key: &ChatKey, chat: &Chat) -> Result<()> {
+    match (store, history_path(config, key)) {
+        (Some(store), _) => store.save_chat(&chat_name(key), &serde_json::to_string(chat)?)
+            .with_context(|| format!("Failed to save chat {:?}", chat_name(key))),
+        (None, Some(path)) => chat.save(&path),
+        (None, None) => Ok(()),
+    }
+}
+
+// Picks up the saved chat if there is one, otherwise starts a new one
+fn open_chat(config: &Config, store: Option<&Store>, key: &ChatKey, speaker: Option<&SpeakerProfile>) -> Chat {
+    let saved = match store {
+        Some(store) => store.chat(&chat_name(key))
+            .and_then(|json| json.map(|json| Chat::parse(&json)).transpose())
+            .with_context(|| format!("Failed to load chat {:?}", chat_name(key)))
+            .map(|chat| chat.map(|chat| (chat, format!("{:?}", chat_name(key))))),
+        None => history_path(config, key)
+            .filter(|path| path.exists())
+            .map(|path| Chat::load(&path).map(|chat| (chat, format!("{:?}", path))))
+            .transpose(),
+    };
+    match saved {
+        Ok(None) => new_chat(config, key, speaker),
+        Ok(Some((mut chat, from))) => {
+            println!("Resuming chat from {}", from);
+            // The template or date may have changed since
+            chat.replace_system(system_prompt(config, key, context_prompt(config, key)));
+            configure_chat(config, &mut chat);
+            chat
+        },
+        Err(err) => {
+            eprintln!("{:?}", err);
+            new_chat(config, key, speaker)
+        }
+    }
+}
+
+const DEFAULT_PROMPT_TEMPLATE: &str = include_str!("../prompt.txt");
+
+// The prompt template (chat.prompt_path, or the built-in one) with
+// {{persona}}, {{assistant_name}}, {{wakewords}} and {{date}} filled in
+fn system_prompt(config: &Config, key: &ChatKey, persona: &str) -> String {
+    let template = match &config.chat.prompt_path {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read the prompt template {:?}, using the built-in one: {:?}", path, err);
+            DEFAULT_PROMPT_TEMPLATE.to_string()
+        }),
+        None => DEFAULT_PROMPT_TEMPLATE.to_string(),
+    };
+    let wakewords: Vec<&str> = std::iter::once(config.wakeword.name.as_str())
+        .chain(config.personas.iter().map(|persona| persona.wakeword.as_str()))
+        .collect();
+
+    [
+        ("persona", persona.to_string()),
+        ("assistant_name", key.0.clone()),
+        ("wakewords", wakewords.join(", ")),
+        ("date", today()),
+    ].iter().fold(template, |prompt, (name, value)| prompt.replace(&format!("{{{{{}}}}}", name), value))
+}
+
+fn new_chat(config: &Config, key: &ChatKey, speaker: Option<&SpeakerProfile>) -> Chat {
+    let persona = context_prompt(config, key);
+    let mut chat = Chat::new();
+    chat.push_system(system_prompt(config, key, persona));
+    if config.whisper.language != "en" {
+        chat.push_system("When the user input has a \"language\" field, the user spoke that language, so respond in it.");
+    }
+    if let Some(speaker) = speaker {
+        chat.push_system(format!("You're talking to {}. {}", speaker.name, speaker.preferences).trim_end());
+    }
+    chat.push_assistant(r#"{"type": "response", "response": "Alright, let's get started!"}"#);
+    chat.push_user(format!(r#"{{"type": "user", "content": "{}"}}"#, "fje and the ant and joke"));
+    chat.push_assistant(r#"{"type": "unclear", "response": "Sorry I'm not sure what you just said there. Can you rephrase that or provide more info?"}"#);
+    chat.mark_seed();
+    configure_chat(config, &mut chat);
+    chat
+}
+
+// Settings that aren't saved with the chat
+fn configure_chat(config: &Config, chat: &mut Chat) {
+    chat.set_tools(tools::schemas(&config.tools));
+    chat.set_response_schema(Some(crate::response_schema()));
+    chat.set_token_model(&config.llm.cloud.model);
+    chat.set_max_entries(config.chat.max_entries);
+    chat.set_token_budget(config.chat.token_budget);
+}
+
@@ -0,0 +1,55 @@
+use nnnoiseless::DenoiseState;
+
+// RNNoise works on 480 sample frames at 48 kHz, the pipeline runs at 16 kHz so
+// each 160 sample chunk is upsampled by 3 on the way in and decimated on the way out
+const FACTOR: usize = 3;
+const CHUNK: usize = DenoiseState::FRAME_SIZE / FACTOR;
+
+pub struct Denoiser {
+    state: Box<DenoiseState<'static>>,
+    pending: Vec<f32>,
+    last: f32,
+    frame_in: Vec<f32>,
+    frame_out: Vec<f32>,
+}
+impl Denoiser {
+    pub fn new() -> Self {
+        Denoiser {
+            state: DenoiseState::new(),
+            pending: Vec::with_capacity(CHUNK),
+            last: 0.,
+            frame_in: vec![0.; DenoiseState::FRAME_SIZE],
+            frame_out: vec![0.; DenoiseState::FRAME_SIZE],
+        }
+    }
+
+    // Output lags the input by up to one chunk
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let mut output = Vec::with_capacity(samples.len() + CHUNK);
+        for &sample in samples {
+            self.pending.push(sample);
+            if self.pending.len() == CHUNK {
+                self.process_chunk(&mut output);
+                self.pending.clear();
+            }
+        }
+        output
+    }
+
+    fn process_chunk(&mut self, output: &mut Vec<f32>) {
+        // Linear interpolation up, RNNoise expects i16 range samples
+        for (i, &sample) in self.pending.iter().enumerate() {
+            for j in 0..FACTOR {
+                let t = (j + 1) as f32 / FACTOR as f32;
+                self.frame_in[i * FACTOR + j] = (self.last * (1. - t) + sample * t) * i16::MAX as f32;
+            }
+            self.last = sample;
+        }
+
+        self.state.process_frame(&mut self.frame_out, &self.frame_in);
+
+        // Averaging on the way down also filters the interpolation images
+        output.extend(self.frame_out.chunks_exact(FACTOR)
+            .map(|frame| frame.iter().sum::<f32>() / FACTOR as f32 / i16::MAX as f32));
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::audio::{Capture, SAMPLE_RATE};
+use crate::config::{Config, RecordingConfig};
+use crate::recording;
+use crate::vad;
+
+// Silence kept around the trimmed clip
+const PADDING_MS: usize = 200;
+
+// Sentences to read out when enrolling a speaker, different ones cover more of
+// their voice
+const SPEAKER_PHRASES: &[&str] = &[
+    "What's the weather going to be like tomorrow?",
+    "Set a timer for twelve minutes.",
+    "How far away is the moon?",
+    "Remind me to call my brother on Sunday.",
+    "Play something quiet and relaxing.",
+    "What should I cook for dinner tonight?",
+];
+
+// Guided recording of clips: prompts for each one (going through phrases),
+// trims it to the speech with the VAD, and numbers them after any clips
+// already in `dir`
+pub async fn record_clips(config: &Config, dir: &Path, count: usize, phrases: &[&str]) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let host = cpal::default_host();
+    let mut capture = Capture::start(&host, &config.audio, config.debug.verbosity)?;
+    let mut vad = vad::create(&config.vad)?;
+
+    // Also gives the energy VAD its noise floor
+    println!("Stay quiet for a moment...");
+    let quiet_until = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < quiet_until {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let samples: Vec<f32> = capture.consumer.pop_iter().collect();
+        vad.process(&samples)?;
+    }
+
+    let mut index = (0..).find(|i| !dir.join(format!("{}.wav", i)).exists()).unwrap();
+    let mut recorded = Vec::new();
+    while recorded.len() < count {
+        println!("({}/{}) Say {:?}", recorded.len() + 1, count, phrases[recorded.len() % phrases.len()]);
+        capture.consumer.clear();
+
+        let mut clip: Vec<f32> = Vec::new();
+        let mut voiced: Option<(usize, usize)> = None;
+        let mut silent_since = Instant::now();
+        let started = Instant::now();
+        loop {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let samples: Vec<f32> = capture.consumer.pop_iter().collect();
+            let voice = vad.process(&samples)?;
+            clip.extend_from_slice(&samples);
+
+            if voice {
+                voiced = Some((voiced.map_or(clip.len() - samples.len(), |(start, _)| start), clip.len()));
+                silent_since = Instant::now();
+            }
+            let done_speaking = voiced.is_some() && silent_since.elapsed() > Duration::from_millis(600);
+            if done_speaking || started.elapsed() > Duration::from_secs(5) {
+                break;
+            }
+        }
+
+        let Some((start, end)) = voiced else {
+            println!("Didn't hear anything, try again");
+            continue;
+        };
+        let padding = SAMPLE_RATE as usize * PADDING_MS / 1000;
+        let trimmed = &clip[start.saturating_sub(padding)..(end + padding).min(clip.len())];
+
+        let path = dir.join(format!("{}.wav", index));
+        recording::write_wav(&path, trimmed, SAMPLE_RATE, &RecordingConfig::default())?;
+        println!("Saved {:?} ({:.1}s)", path, trimmed.len() as f32 / SAMPLE_RATE as f32);
+        index += 1;
+        recorded.push(path);
+    }
+
+    Ok(recorded)
+}
+
+pub async fn record_wakeword(config: &Config, dir: &Path, count: usize) -> Result<()> {
+    record_clips(config, dir, count, &[&config.wakeword.name]).await?;
+    println!("Done, use them with --wakeword-dir {:?} or build a model with train-wakeword {:?}", dir, dir);
+    Ok(())
+}
+
+// Clips of someone talking normally, for speaker identification
+pub async fn enroll_speaker(config: &Config, name: &str, dir: &Path, count: usize) -> Result<()> {
+    let clips = record_clips(config, dir, count, SPEAKER_PHRASES).await?;
+    println!("Done, add {:?} to the config:", name);
+    println!("[[speaker.profiles]]");
+    println!("name = {:?}", name);
+    println!("clips = {:?}", clips.iter().map(|clip| clip.to_string_lossy()).collect::<Vec<_>>());
+    Ok(())
+}
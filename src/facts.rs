@@ -0,0 +1,173 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, Context};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::config::FactsConfig;
+use crate::store::Store;
+use crate::transcript::Transcript;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Fact {
+    // Its row in the store
+    #[serde(skip)]
+    id: Option<i64>,
+    text: String,
+    created: DateTime<Utc>,
+}
+
+// What the user asked to be remembered ("remember that my bike lock code is
+// 4312"), given to the model with every prompt. Kept in the store, or
+// facts.path without one
+pub struct Facts {
+    config: FactsConfig,
+    store: Option<Arc<Store>>,
+    facts: Mutex<Vec<Fact>>,
+}
+impl Facts {
+    pub fn new(config: &FactsConfig, store: Option<Arc<Store>>) -> Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let facts = match &store {
+            Some(store) => store.facts()?.into_iter()
+                .map(|(id, text, created)| Fact { id: Some(id), text, created })
+                .collect(),
+            None if config.enabled && path.exists() => {
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read facts {:?}", path))?;
+                serde_json::from_str(&contents).with_context(|| format!("Failed to parse facts {:?}", path))?
+            },
+            None => Vec::new(),
+        };
+        Ok(Facts { config: config.clone(), store, facts: Mutex::new(facts) })
+    }
+
+    // Remembering, forgetting and listing facts, None when it's none of those
+    pub fn handle(&self, transcript: &Transcript) -> Option<String> {
+        let config = &self.config;
+        if !config.enabled {
+            return None;
+        }
+        let text = transcript.normalized();
+        if config.list_phrases.iter().any(|phrase| text.contains(&phrase.to_lowercase())) {
+            let facts = self.facts.lock().unwrap();
+            return Some(match facts.is_empty() {
+                true => config.empty_response.clone(),
+                false => {
+                    let list = facts.iter().map(|fact| fact.text.as_str()).collect::<Vec<_>>().join("; ");
+                    config.list_response.replace("{facts}", &list)
+                },
+            });
+        }
+        if let Some(fact) = command(transcript, &config.remember_phrases) {
+            return Some(match self.remember(fact) {
+                Ok(()) => config.remember_response.clone(),
+                Err(err) => {
+                    eprintln!("Failed to remember {:?}: {:?}", fact, err);
+                    "Sorry, I couldn't save that.".to_string()
+                },
+            });
+        }
+        if let Some(fact) = command(transcript, &config.forget_phrases) {
+            return Some(match self.forget(fact) {
+                Ok(true) => config.forget_response.clone(),
+                Ok(false) => config.not_found_response.clone(),
+                Err(err) => {
+                    eprintln!("Failed to forget {:?}: {:?}", fact, err);
+                    "Sorry, I couldn't forget that.".to_string()
+                },
+            });
+        }
+        None
+    }
+
+    // For the context of a prompt, None without any facts
+    pub fn note(&self) -> Option<String> {
+        let facts = self.facts.lock().unwrap();
+        if !self.config.enabled || facts.is_empty() {
+            return None;
+        }
+        let list = facts.iter()
+            .map(|fact| format!("- {} ({})", fact.text, fact.created.with_timezone(&chrono::Local).format("%B %-d %Y")))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(format!("The user asked you to remember these, in their words:\n{}", list))
+    }
+
+    fn remember(&self, text: &str) -> Result<()> {
+        let created = Utc::now();
+        let id = match &self.store {
+            Some(store) => Some(store.add_fact(text, created)?),
+            None => None,
+        };
+        let mut facts = self.facts.lock().unwrap();
+        facts.push(Fact { id, text: text.to_string(), created });
+        self.save(&facts)
+    }
+
+    // The one sharing the most words with what was said, false unless it has
+    // most of them
+    fn forget(&self, text: &str) -> Result<bool> {
+        let spoken = keywords(text);
+        let mut facts = self.facts.lock().unwrap();
+        let best = facts.iter()
+            .enumerate()
+            .map(|(i, fact)| {
+                let fact = keywords(&fact.text);
+                (i, spoken.iter().filter(|word| fact.contains(word)).count())
+            })
+            .filter(|&(_, shared)| shared * 2 > spoken.len())
+            .max_by_key(|&(_, shared)| shared);
+        let Some((i, _)) = best else { return Ok(false) };
+
+        let fact = facts.remove(i);
+        if let (Some(store), Some(id)) = (&self.store, fact.id) {
+            store.remove_fact(id)?;
+        }
+        self.save(&facts)?;
+        Ok(true)
+    }
+
+    fn save(&self, facts: &[Fact]) -> Result<()> {
+        if self.store.is_some() {
+            return Ok(());
+        }
+        std::fs::write(&self.config.path, serde_json::to_string_pretty(facts)?)
+            .with_context(|| format!("Failed to save facts {:?}", self.config.path))
+    }
+}
+
+// Too common to tell facts apart
+const STOPWORDS: &[&str] = &[
+    "the", "and", "you", "your", "that", "this", "about", "what", "for", "with", "was", "are",
+    "has", "have", "had", "its", "from", "there", "their", "they", "them", "then", "than", "but", "not",
+    "all", "any", "can", "who", "how", "when", "where", "which", "will", "just", "into", "out", "also",
+];
+
+// The words that say what a fact is about
+fn keywords(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 2)
+        .map(str::to_lowercase)
+        .filter(|word| !STOPWORDS.contains(&word.as_str()))
+        .collect()
+}
+
+// What comes after one of the phrases, in its original casing. Only when the
+// phrase is near the start ("computer, remember that ..."), so it isn't
+// picked out of the middle of a question
+fn command<'a>(transcript: &'a Transcript, phrases: &[String]) -> Option<&'a str> {
+    let text = transcript.normalized();
+    let end = phrases.iter()
+        .filter_map(|phrase| {
+            let phrase = phrase.to_lowercase();
+            text.find(&phrase).map(|start| (start, start + phrase.len()))
+        })
+        .filter(|&(start, _)| text[..start].split_whitespace().count() <= 2)
+        .max_by_key(|&(start, end)| end - start)
+        .map(|(_, end)| end)?;
+    let rest = transcript.original().get(end..).unwrap_or(&text[end..]);
+    let rest = rest.trim_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation());
+    (!rest.is_empty()).then_some(rest)
+}
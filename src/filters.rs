@@ -0,0 +1,62 @@
+use crate::config::AgcConfig;
+
+// Automatic gain control, slowly adjusts the gain to bring the input level
+// (RMS) to the target
+pub struct Agc {
+    target: f32,
+    max_gain: f32,
+    envelope: f32,
+    gain: f32,
+    // Per-sample smoothing coefficients
+    attack: f32,
+    release: f32,
+}
+impl Agc {
+    pub fn new(config: &AgcConfig, sample_rate: u32) -> Self {
+        let coefficient = |ms: f32| (-1. / (sample_rate as f32 * ms / 1000.)).exp();
+        Agc {
+            target: config.target_level,
+            max_gain: config.max_gain,
+            envelope: config.target_level,
+            gain: 1.,
+            attack: coefficient(config.attack_ms),
+            release: coefficient(config.release_ms),
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        // Envelope rises quickly and falls slowly, so loud onsets aren't clipped
+        let power = sample * sample;
+        let coefficient = if power > self.envelope * self.envelope { self.attack } else { self.release };
+        self.envelope = (coefficient * self.envelope * self.envelope + (1. - coefficient) * power).sqrt();
+
+        let wanted = (self.target / self.envelope.max(1e-6)).clamp(1. / self.max_gain, self.max_gain);
+        self.gain = self.release * self.gain + (1. - self.release) * wanted;
+
+        (sample * self.gain).clamp(-1., 1.)
+    }
+}
+
+// First order high-pass, removes DC offset and low frequency hum
+pub struct HighPass {
+    alpha: f32,
+    last_input: f32,
+    last_output: f32,
+}
+impl HighPass {
+    pub fn new(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let rc = 1. / (2. * std::f32::consts::PI * cutoff_hz);
+        let dt = 1. / sample_rate as f32;
+        HighPass {
+            alpha: rc / (rc + dt),
+            last_input: 0.,
+            last_output: 0.,
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.last_output = self.alpha * (self.last_output + sample - self.last_input);
+        self.last_input = sample;
+        self.last_output
+    }
+}
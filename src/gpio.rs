@@ -0,0 +1,52 @@
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use anyhow::Result;
+use rppal::gpio::{Gpio, OutputPin};
+
+// Reports presses (true) and releases (false) of a button wired between `pin`
+// and ground
+pub fn listen(pin: u8) -> Result<Receiver<bool>> {
+    let pin = Gpio::new()?.get(pin)?.into_input_pullup();
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut pressed = false;
+        loop {
+            // Polling slowly enough also debounces the button
+            std::thread::sleep(Duration::from_millis(20));
+            let state = pin.is_low();
+            if state != pressed {
+                pressed = state;
+                if sender.send(state).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+pub struct Led {
+    pin: OutputPin,
+    on: bool,
+}
+impl Led {
+    pub fn new(pin: u8) -> Result<Self> {
+        Ok(Led {
+            pin: Gpio::new()?.get(pin)?.into_output_low(),
+            on: false,
+        })
+    }
+    pub fn set(&mut self, on: bool) {
+        if on != self.on {
+            self.on = on;
+            if on {
+                self.pin.set_high();
+            } else {
+                self.pin.set_low();
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::mpsc::{self, Receiver};
+
+use anyhow::{Result, Context, anyhow};
+use rdev::{EventType, Key};
+
+// Reports presses (true) and releases (false) of a global key, by rdev key name
+// e.g. "F9" or "ControlRight"
+pub fn listen(key: &str) -> Result<Receiver<bool>> {
+    let key: Key = serde_json::from_value(serde_json::Value::String(key.to_string()))
+        .with_context(|| format!("Unknown push-to-talk key {:?}", key))?;
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut pressed = false;
+        let result = rdev::listen(move |event| {
+            let state = match event.event_type {
+                EventType::KeyPress(pressed_key) if pressed_key == key => true,
+                EventType::KeyRelease(released_key) if released_key == key => false,
+                _ => return,
+            };
+            // Holding a key down repeats the press
+            if state != pressed {
+                pressed = state;
+                let _ = sender.send(state);
+            }
+        });
+        if let Err(err) = result.map_err(|err| anyhow!("{:?}", err)) {
+            eprintln!("Push-to-talk stopped working: {:?}", err);
+        }
+    });
+
+    Ok(receiver)
+}
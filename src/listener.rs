@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::TimingConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeakingState {
+    Silent,
+    Speaking,
+    // Silence after speech, finished once it lasts for `hangover`
+    Pending { end: Instant, hangover: Duration },
+    // Just answered, speech before `until` starts a command without the wakeword
+    FollowUp { until: Instant },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenEvent {
+    Wakeword,
+    // Push-to-talk, the command lasts until Stop regardless of the VAD
+    Start,
+    Stop,
+    // The answer (or a re-prompt) finished playing, wait a while for a reply
+    FollowUp { length: Duration },
+    // energy is the RMS level of the audio since the last frame
+    Frame { voice: bool, energy: f32 },
+}
+
+// How many recent frames (roughly 10ms each) the endpointer looks back over
+const RECENT_FRAMES: usize = 50;
+
+// The wakeword/end-of-speech state machine. Time is always passed in, so it can
+// be driven with synthetic events and a fake clock as well as from the audio loop
+pub struct Listener {
+    state: SpeakingState,
+    detection_start: Instant,
+    timing: TimingConfig,
+    // Started by push-to-talk, so the VAD doesn't get to end it
+    manual: bool,
+    // Energy of every voiced frame this command, and the last few frames
+    voiced_energy_sum: f32,
+    voiced_frames: usize,
+    recent: VecDeque<(bool, f32)>,
+}
+impl Listener {
+    pub fn new(timing: TimingConfig, now: Instant) -> Self {
+        Listener {
+            state: SpeakingState::Silent,
+            detection_start: now,
+            timing,
+            manual: false,
+            voiced_energy_sum: 0.,
+            voiced_frames: 0,
+            recent: VecDeque::with_capacity(RECENT_FRAMES),
+        }
+    }
+    pub fn state(&self) -> SpeakingState {
+        self.state
+    }
+    pub fn is_silent(&self) -> bool {
+        matches!(self.state, SpeakingState::Silent)
+    }
+    pub fn is_follow_up(&self) -> bool {
+        matches!(self.state, SpeakingState::FollowUp { .. })
+    }
+
+    // Returns true once the command is finished and should be processed
+    pub fn handle(&mut self, event: ListenEvent, now: Instant) -> bool {
+        let mut finished = false;
+        match (self.state, event) {
+            (SpeakingState::Silent | SpeakingState::FollowUp { .. }, ListenEvent::Wakeword | ListenEvent::Start) => {
+                self.start(now, matches!(event, ListenEvent::Start));
+            },
+            (SpeakingState::Silent, ListenEvent::FollowUp { length }) => {
+                if !length.is_zero() {
+                    self.state = SpeakingState::FollowUp { until: now + length };
+                }
+            },
+            (SpeakingState::FollowUp { until }, ListenEvent::Frame { voice, energy }) => {
+                if voice {
+                    self.start(now, false);
+                    self.record(voice, energy);
+                } else if now > until {
+                    self.state = SpeakingState::Silent;
+                }
+            },
+            (SpeakingState::FollowUp { .. }, ListenEvent::Stop) => self.state = SpeakingState::Silent,
+            (SpeakingState::Silent | SpeakingState::FollowUp { .. }, _) |
+            (_, ListenEvent::Wakeword | ListenEvent::Start | ListenEvent::FollowUp { .. }) => {},
+            (_, ListenEvent::Stop) => finished = true,
+            (_, ListenEvent::Frame { .. }) if self.manual => {},
+            (SpeakingState::Speaking, ListenEvent::Frame { voice, energy }) => {
+                if !voice {
+                    self.state = SpeakingState::Pending { end: now, hangover: self.hangover(now) };
+                }
+                self.record(voice, energy);
+            },
+            (SpeakingState::Pending { end, hangover }, ListenEvent::Frame { voice, energy }) => {
+                self.record(voice, energy);
+                if voice {
+                    self.state = SpeakingState::Speaking;
+                } else if now - end > hangover &&
+                    now - self.detection_start > Duration::from_millis(self.timing.min_command_ms) {
+                    finished = true;
+                }
+            },
+        }
+
+        // Don't wait forever for the user to stop talking (or for background noise to stop)
+        if matches!(self.state, SpeakingState::Speaking | SpeakingState::Pending { .. }) && !finished &&
+            now - self.detection_start > Duration::from_millis(self.timing.command_timeout_ms) {
+            println!("Command timed out");
+            finished = true;
+        }
+
+        if finished {
+            self.state = SpeakingState::Silent;
+        }
+        finished
+    }
+
+    fn start(&mut self, now: Instant, manual: bool) {
+        self.state = SpeakingState::Speaking;
+        self.detection_start = now;
+        self.manual = manual;
+        self.voiced_energy_sum = 0.;
+        self.voiced_frames = 0;
+        self.recent.clear();
+    }
+
+    fn record(&mut self, voice: bool, energy: f32) {
+        if voice {
+            self.voiced_energy_sum += energy;
+            self.voiced_frames += 1;
+        }
+        if self.recent.len() == RECENT_FRAMES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((voice, energy));
+    }
+
+    // How much silence ends the command. With adaptive endpointing this is
+    // shorter when the command sounds complete and longer after a hesitation
+    fn hangover(&self, now: Instant) -> Duration {
+        let base = self.timing.silence_hangover_ms as f32;
+        if !self.timing.adaptive_endpointing || self.voiced_frames == 0 {
+            return Duration::from_millis(self.timing.silence_hangover_ms);
+        }
+
+        let mut hangover = base;
+        // Long commands are usually whole sentences by the time there's a pause
+        if now - self.detection_start > Duration::from_millis(self.timing.long_command_ms) {
+            hangover *= 0.75;
+        }
+
+        let voiced: Vec<f32> = self.recent.iter().filter(|(voice, _)| *voice).map(|(_, energy)| *energy).collect();
+        if voiced.len() >= 10 {
+            let average = self.voiced_energy_sum / self.voiced_frames as f32;
+            let tail = &voiced[voiced.len() - 10..];
+            let tail_energy = tail.iter().sum::<f32>() / tail.len() as f32;
+
+            // Trailing off at the end of a sentence
+            if tail_energy < average * 0.5 {
+                hangover *= 0.7;
+            }
+
+            // A drawn out "uhh"/"umm" is one long, steady stretch of voice
+            let sustained = self.recent.iter().rev().take(30).all(|(voice, _)| *voice);
+            let mean = voiced.iter().sum::<f32>() / voiced.len() as f32;
+            let variance = voiced.iter().map(|energy| (energy - mean).powi(2)).sum::<f32>() / voiced.len() as f32;
+            if sustained && mean > 0. && variance.sqrt() / mean < 0.15 {
+                hangover *= 1.6;
+            }
+        }
+
+        let hangover = hangover.clamp(self.timing.min_silence_hangover_ms as f32, self.timing.max_silence_hangover_ms as f32);
+        Duration::from_millis(hangover as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_MS: u64 = 10;
+
+    // Feeds one frame every FRAME_MS from `from` up to (not including) `to`,
+    // returning when the listener said the command was finished
+    fn frames(listener: &mut Listener, base: Instant, from: u64, to: u64, voice: bool) -> Option<u64> {
+        (from..to).step_by(FRAME_MS as usize)
+            .find(|&ms| listener.handle(ListenEvent::Frame { voice, energy: 0.1 }, base + Duration::from_millis(ms)))
+    }
+
+    #[test]
+    fn wakeword_speech_then_silence_finishes_after_hangover() {
+        let timing = TimingConfig::default();
+        let base = Instant::now();
+        let mut listener = Listener::new(timing.clone(), base);
+
+        assert!(!listener.handle(ListenEvent::Wakeword, base));
+        assert_eq!(listener.state(), SpeakingState::Speaking);
+        assert_eq!(frames(&mut listener, base, 0, 2000, true), None);
+
+        // Silence starts at 2000ms, the command ends once it's lasted longer
+        // than the hangover
+        let finished = frames(&mut listener, base, 2000, 5000, false);
+        assert_eq!(finished, Some(2000 + timing.silence_hangover_ms + FRAME_MS));
+        assert!(listener.is_silent());
+    }
+
+    #[test]
+    fn speech_without_wakeword_never_finishes() {
+        let base = Instant::now();
+        let mut listener = Listener::new(TimingConfig::default(), base);
+
+        assert_eq!(frames(&mut listener, base, 0, 3000, true), None);
+        assert_eq!(frames(&mut listener, base, 3000, 20000, false), None);
+        assert!(listener.is_silent());
+    }
+
+    #[test]
+    fn expired_follow_up_goes_back_to_idle() {
+        let base = Instant::now();
+        let mut listener = Listener::new(TimingConfig::default(), base);
+
+        listener.handle(ListenEvent::FollowUp { length: Duration::from_millis(8000) }, base);
+        assert!(listener.is_follow_up());
+        assert_eq!(frames(&mut listener, base, 0, 8000, false), None);
+        assert!(listener.is_follow_up());
+        assert_eq!(frames(&mut listener, base, 8000, 8100, false), None);
+        assert!(listener.is_silent());
+
+        // Speech after that needs the wakeword again
+        assert_eq!(frames(&mut listener, base, 9000, 12000, true), None);
+        assert!(listener.is_silent());
+    }
+
+    #[test]
+    fn speaking_too_long_times_out() {
+        let timing = TimingConfig::default();
+        let base = Instant::now();
+        let mut listener = Listener::new(timing.clone(), base);
+
+        listener.handle(ListenEvent::Wakeword, base);
+        let finished = frames(&mut listener, base, 0, 20000, true);
+        assert_eq!(finished, Some(timing.command_timeout_ms + FRAME_MS));
+    }
+}
@@ -0,0 +1,175 @@
+use std::num::NonZeroU32;
+use std::sync::mpsc::{self, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow, bail};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::{AddBos, LlamaChatMessage, LlamaChatTemplate, LlamaModel, Special};
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::sampling::LlamaSampler;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use crate::chat::{Backend, Entry};
+use crate::llm::{Completion, LanguageModel, RequestOptions, Usage};
+
+const DEFAULT_CONTEXT_SIZE: u32 = 4096;
+const DEFAULT_MAX_TOKENS: u32 = 512;
+// Everything that fits, only matters when built with a GPU feature
+const GPU_LAYERS: u32 = 999;
+
+type Job = (Vec<Entry>, Option<UnboundedSender<String>>, oneshot::Sender<Result<(Entry, Usage)>>);
+
+// A GGUF model run in-process by llama.cpp, on its own thread like the
+// transcriber since a completion keeps it busy for seconds
+pub struct LlamaCpp {
+    jobs: Sender<Job>,
+}
+impl LlamaCpp {
+    pub fn new(backend: &Backend) -> Result<Self> {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (ready_sender, ready) = mpsc::channel();
+        let backend = backend.clone();
+        std::thread::spawn(move || {
+            let loaded = (|| -> Result<_> {
+                let mut llama = LlamaBackend::init()?;
+                llama.void_logs();
+                println!("Loading {:?} with llama.cpp...", backend.model);
+                let params = LlamaModelParams::default().with_n_gpu_layers(GPU_LAYERS);
+                let model = LlamaModel::load_from_file(&llama, &backend.model, &params)
+                    .map_err(|err| anyhow!("Failed to load {:?}: {}", backend.model, err))?;
+                let template = model.chat_template(None)?;
+                Ok((llama, model, template))
+            })();
+            let (llama, model, template) = match loaded {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                    return;
+                }
+            };
+            let context_size = backend.context_size.unwrap_or(DEFAULT_CONTEXT_SIZE);
+            let params = LlamaContextParams::default()
+                .with_n_ctx(NonZeroU32::new(context_size))
+                .with_n_batch(context_size);
+            let mut ctx = match model.new_context(&llama, params) {
+                Ok(ctx) => ctx,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err.into()));
+                    return;
+                }
+            };
+            let _ = ready_sender.send(Ok(()));
+
+            let generator = Generator { backend: &backend, model: &model, template: &template, context_size };
+            for (messages, deltas, reply) in job_receiver {
+                let _ = reply.send(generator.generate(&mut ctx, &messages, deltas));
+            }
+        });
+        ready.recv()??;
+
+        Ok(LlamaCpp { jobs })
+    }
+}
+impl LanguageModel for LlamaCpp {
+    fn request<'a>(&'a self, messages: &'a [Entry], _options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a> {
+        Box::pin(async move {
+            let (reply, completion) = oneshot::channel();
+            self.jobs.send((messages.to_vec(), deltas, reply)).map_err(|_| anyhow!("llama.cpp worker stopped"))?;
+            completion.await?
+        })
+    }
+}
+
+struct Generator<'a> {
+    backend: &'a Backend,
+    model: &'a LlamaModel,
+    template: &'a LlamaChatTemplate,
+    context_size: u32,
+}
+impl Generator<'_> {
+    fn sampler(&self) -> LlamaSampler {
+        let temperature = self.backend.temperature.unwrap_or(0.8);
+        if temperature <= 0.0 {
+            return LlamaSampler::greedy();
+        }
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.subsec_nanos());
+        LlamaSampler::chain_simple([
+            LlamaSampler::top_p(self.backend.top_p.unwrap_or(0.95), 1),
+            LlamaSampler::temp(temperature),
+            LlamaSampler::dist(seed),
+        ])
+    }
+
+    fn generate(&self, ctx: &mut LlamaContext, messages: &[Entry], deltas: Option<UnboundedSender<String>>) -> Result<(Entry, Usage)> {
+        let model = self.model;
+        let chat = messages.iter()
+            .map(|entry| {
+                let role = match entry {
+                    Entry::System(_) => "system",
+                    Entry::Assistant(_) | Entry::ToolCalls(_) => "assistant",
+                    // Text only, the picture is left out
+                    Entry::User(_) | Entry::UserImage { .. } => "user",
+                    Entry::Tool { .. } => "tool",
+                };
+                LlamaChatMessage::new(role.to_string(), entry.content().to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // The template already starts with the BOS token where the model wants one
+        let prompt = model.apply_chat_template(self.template, &chat, true)?;
+        let tokens = model.str_to_token(&prompt, AddBos::Never)?;
+        if tokens.len() >= self.context_size as usize {
+            bail!("The chat is {} tokens, over the {} token context (llm context_size)", tokens.len(), self.context_size);
+        }
+
+        // Every request starts over, the chat can change anywhere between them
+        ctx.clear_kv_cache();
+        let mut batch = LlamaBatch::new(self.context_size as usize, 1);
+        for (i, token) in tokens.iter().enumerate() {
+            batch.add(*token, i as i32, &[0], i == tokens.len() - 1)?;
+        }
+        ctx.decode(&mut batch)?;
+
+        let mut sampler = self.sampler();
+        let mut position = tokens.len() as i32;
+        let mut content = String::new();
+        // A token can end partway through a character
+        let mut pending: Vec<u8> = Vec::new();
+        let max_tokens = self.backend.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        for _ in 0..max_tokens {
+            if position as u32 >= self.context_size {
+                break;
+            }
+            let token = sampler.sample(ctx, batch.n_tokens() - 1);
+            sampler.accept(token);
+            if model.is_eog_token(token) {
+                break;
+            }
+
+            pending.extend(model.token_to_bytes(token, Special::Plaintext)?);
+            let valid = match std::str::from_utf8(&pending) {
+                Ok(piece) => piece.len(),
+                Err(err) => err.valid_up_to(),
+            };
+            if valid > 0 {
+                let piece = String::from_utf8_lossy(&pending[..valid]).into_owned();
+                pending.drain(..valid);
+                content.push_str(&piece);
+                if let Some(deltas) = &deltas {
+                    let _ = deltas.send(piece);
+                }
+            }
+
+            batch.clear();
+            batch.add(token, position, &[0], true)?;
+            position += 1;
+            ctx.decode(&mut batch)?;
+        }
+
+        let usage = Usage { prompt_tokens: tokens.len() as u64, completion_tokens: position as u64 - tokens.len() as u64 };
+        Ok((Entry::Assistant(content), usage))
+    }
+}
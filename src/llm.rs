@@ -0,0 +1,380 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context, anyhow};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+
+use crate::chat::{Api, Backend, Entry, ResponseFormat, ToolCall};
+use crate::cache::ResponseCache;
+use crate::config::{CacheConfig, FactsConfig, LlmConfig, MemoryConfig, UsageConfig};
+use crate::facts::Facts;
+use crate::memory::Memory;
+use crate::store::Store;
+use crate::usage::{Metered, UsageTracker};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+pub type Completion<'a> = BoxFuture<'a, Result<(Entry, Usage)>>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+// What's sent along with the messages. Tools are OpenAI tools API schemas and
+// schema is the JSON schema replies should follow, backends that can't do
+// either ignore them
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    pub tools: Vec<Value>,
+    pub schema: Option<Value>,
+}
+
+// Something that can continue a chat, returning the reply and the tokens used.
+// Pieces of the reply go to deltas as they're generated, when given
+pub trait LanguageModel: Send + Sync {
+    fn request<'a>(&'a self, messages: &'a [Entry], options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a>;
+    // Run once at startup, to get ready for the first request
+    fn prepare(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+pub fn create(backend: &Backend, client: &reqwest::Client) -> Result<Box<dyn LanguageModel>> {
+    Ok(match backend.api {
+        Api::OpenAi | Api::Azure => Box::new(OpenAi { backend: backend.clone(), client: client.clone() }),
+        Api::Ollama => Box::new(crate::ollama::Ollama::new(backend, client)),
+        Api::Anthropic => Box::new(crate::anthropic::Anthropic::new(backend, client)),
+        #[cfg(feature = "llama")]
+        Api::LlamaCpp => Box::new(crate::llama::LlamaCpp::new(backend)?),
+        #[cfg(not(feature = "llama"))]
+        Api::LlamaCpp => anyhow::bail!("Built without llama.cpp support, rebuild with --features llama"),
+    })
+}
+
+// Goes through llm.proxy when set (reqwest picks up $HTTPS_PROXY and
+// $HTTP_PROXY itself otherwise), trusting llm.ca_cert on top of the system's
+// certificates
+pub fn http_client(config: &LlmConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy {:?}", proxy))?);
+    }
+    if let Some(path) = &config.ca_cert {
+        let pem = std::fs::read(path).with_context(|| format!("Failed to read CA certificate {:?}", path))?;
+        let cert = reqwest::Certificate::from_pem(&pem).with_context(|| format!("Invalid CA certificate {:?}", path))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    builder.build().context("Failed to set up the HTTP client")
+}
+
+// The backends from the llm config, set up once and shared by every session
+pub struct LanguageModels {
+    pub cloud: Box<dyn LanguageModel>,
+    pub local: Option<Box<dyn LanguageModel>>,
+    pub usage: Arc<UsageTracker>,
+    // With memory.enabled
+    pub memory: Option<Arc<Memory>>,
+    // "Remember that ..." facts
+    pub facts: Facts,
+    pub cache: ResponseCache,
+    // With store.path, also used by the chats and tools
+    pub store: Option<Arc<Store>>,
+}
+impl LanguageModels {
+    pub async fn new(config: &LlmConfig, usage_config: &UsageConfig, memory_config: &MemoryConfig, facts_config: &FactsConfig, cache_config: &CacheConfig, store: Option<Arc<Store>>) -> Result<Self> {
+        let usage = Arc::new(UsageTracker::new(usage_config, store.clone())?);
+        let client = http_client(config)?;
+        let metered = |model: Box<dyn LanguageModel>, backend: &Backend| -> Box<dyn LanguageModel> {
+            Box::new(Metered { model, backend: backend.clone(), usage: usage.clone() })
+        };
+        // The local one falls back to the cloud instead of retrying
+        let cloud = Box::new(Retrying {
+            model: create(&config.cloud, &client)?,
+            timeout: Duration::from_millis(config.request_timeout_ms),
+            retries: config.retries,
+            delay: Duration::from_millis(config.retry_delay_ms),
+        });
+        let local = match &config.local {
+            Some(local) => Some(metered(create(local, &client)?, local)),
+            None => None,
+        };
+        let models = LanguageModels {
+            cloud: metered(cloud, &config.cloud),
+            local,
+            usage: usage.clone(),
+            memory: match memory_config.enabled {
+                true => Some(Arc::new(Memory::new(memory_config, &client, usage.clone(), store.clone())?)),
+                false => None,
+            },
+            facts: Facts::new(facts_config, store.clone())?,
+            cache: ResponseCache::new(cache_config),
+            store,
+        };
+        // Not being ready yet isn't fatal, the server might come up later
+        for (model, backend) in std::iter::once((&models.cloud, &config.cloud))
+            .chain(models.local.iter().zip(&config.local)) {
+            if let Err(err) = model.prepare().await {
+                eprintln!("Failed to get {} ready: {:?}", backend.name, err);
+            }
+        }
+        Ok(models)
+    }
+}
+
+// An unsuccessful response, kept as is so it can be told apart from
+// failures that aren't worth retrying
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+    pub retry_after: Option<Duration>,
+}
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.body)
+    }
+}
+impl std::error::Error for HttpError {}
+
+pub async fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    let retry_after = res.headers().get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    Err(HttpError { status: res.status(), retry_after, body: res.text().await.unwrap_or_default() }.into())
+}
+
+// Whether a failed request might go through if tried again, and when the
+// server asked for that to be
+fn transient(err: &anyhow::Error) -> (bool, Option<Duration>) {
+    if let Some(err) = err.downcast_ref::<HttpError>() {
+        let transient = err.status == reqwest::StatusCode::TOO_MANY_REQUESTS || err.status.is_server_error();
+        return (transient, err.retry_after);
+    }
+    let transient = err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|err| err.is_timeout() || err.is_connect() || err.is_request() || err.is_body());
+    (transient, None)
+}
+
+// Gives every request a timeout and retries transient failures with
+// exponential backoff. A streamed answer that had already started isn't
+// retried, since it's already being spoken
+struct Retrying {
+    model: Box<dyn LanguageModel>,
+    timeout: Duration,
+    retries: u32,
+    delay: Duration,
+}
+impl Retrying {
+    async fn complete(&self, messages: &[Entry], options: &RequestOptions, deltas: Option<UnboundedSender<String>>) -> Result<(Entry, Usage)> {
+        let mut attempt = 0;
+        loop {
+            let (attempt_deltas, mut receiver) = unbounded_channel::<String>();
+            let request = tokio::time::timeout(self.timeout, self.model.request(messages, options, deltas.is_some().then_some(attempt_deltas)));
+            let mut started = false;
+            let forward = async {
+                while let Some(delta) = receiver.recv().await {
+                    started = true;
+                    if let Some(deltas) = &deltas {
+                        let _ = deltas.send(delta);
+                    }
+                }
+            };
+            let (result, ()) = tokio::join!(request, forward);
+
+            let (err, (transient, retry_after)) = match result {
+                Ok(Ok(completion)) => return Ok(completion),
+                Ok(Err(err)) => {
+                    let transient = transient(&err);
+                    (err, transient)
+                },
+                Err(_) => (anyhow!("Timed out after {:?}", self.timeout), (true, None)),
+            };
+            if !transient || started || attempt >= self.retries {
+                return Err(err);
+            }
+
+            // Jittered by up to half either way so clients don't retry in step
+            let jitter = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.subsec_nanos()) as f32 / 1e9;
+            let delay = retry_after.unwrap_or_else(|| self.delay.mul_f32(2f32.powi(attempt as i32) * (0.5 + jitter)));
+            eprintln!("Request failed ({:#}), retrying in {:?}", err, delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+impl LanguageModel for Retrying {
+    fn request<'a>(&'a self, messages: &'a [Entry], options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a> {
+        Box::pin(self.complete(messages, options, deltas))
+    }
+    fn prepare(&self) -> BoxFuture<'_, Result<()>> {
+        self.model.prepare()
+    }
+}
+
+// Calls on_line with every complete line of a streamed response, until it
+// returns false
+pub async fn read_lines(mut res: reqwest::Response, mut on_line: impl FnMut(&str) -> Result<bool>) -> Result<()> {
+    // Lines can be split across chunks
+    let mut pending: Vec<u8> = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if !on_line(String::from_utf8_lossy(&line).trim())? {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+// The OpenAI chat completions API, which most servers also speak
+pub struct OpenAi {
+    backend: Backend,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: &'a [Entry],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [Value],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+}
+
+impl OpenAi {
+    async fn complete(&self, messages: &[Entry], options: &RequestOptions, deltas: Option<UnboundedSender<String>>) -> Result<(Entry, Usage)> {
+        let backend = &self.backend;
+        let mut request = self.client.post(backend.completions_url())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(key) = &backend.api_key {
+            request = match backend.api {
+                Api::Azure => request.header("api-key", key.trim()),
+                _ => request.bearer_auth(key.trim()),
+            };
+        }
+        let res = request
+            .json(&CompletionRequest {
+                model: &backend.model,
+                messages,
+                tools: &options.tools,
+                response_format: response_format(backend.response_format, options.schema.as_ref()),
+                temperature: backend.temperature,
+                top_p: backend.top_p,
+                max_tokens: backend.max_tokens,
+                stream: deltas.is_some(),
+                stream_options: deltas.is_some().then(|| serde_json::json!({ "include_usage": true })),
+            })
+            .send()
+            .await?;
+        let res = check_status(res).await?;
+
+        let Some(deltas) = deltas else {
+            let mut val = res.json::<Value>().await?;
+
+            let completion: Entry = serde_json::from_value(
+                val.get_mut("choices").context("No choices")?.take()
+                    .get_mut(0).context("0-length choices")?.take()
+                    .get_mut("message").context("No message")?.take()
+            )?;
+            // Local servers don't always report usage
+            return Ok((completion, openai_usage(&val["usage"])));
+        };
+
+        // Server-sent events, one per piece
+        let mut content = String::new();
+        // Tool calls come in pieces too, by index
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        let mut usage = Usage::default();
+        read_lines(res, |line| {
+            let Some(data) = line.strip_prefix("data:") else { return Ok(true) };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok(false);
+            }
+
+            let event: Value = serde_json::from_str(data).context("Invalid completion event")?;
+            if let Some(delta) = event.pointer("/choices/0/delta/content").and_then(Value::as_str) {
+                content.push_str(delta);
+                // Nobody listening anymore is fine, the completion is still wanted
+                let _ = deltas.send(delta.to_string());
+            }
+            for call in event.pointer("/choices/0/delta/tool_calls").and_then(Value::as_array).into_iter().flatten() {
+                let index = call["index"].as_u64().unwrap_or(0) as usize;
+                if tool_calls.len() <= index {
+                    tool_calls.resize_with(index + 1, ToolCall::default);
+                }
+                let tool_call = &mut tool_calls[index];
+                if let Some(id) = call["id"].as_str() {
+                    tool_call.id = id.to_string();
+                }
+                if let Some(name) = call.pointer("/function/name").and_then(Value::as_str) {
+                    tool_call.function.name.push_str(name);
+                }
+                if let Some(arguments) = call.pointer("/function/arguments").and_then(Value::as_str) {
+                    tool_call.function.arguments.push_str(arguments);
+                }
+            }
+            if event["usage"].is_object() {
+                usage = openai_usage(&event["usage"]);
+            }
+            Ok(true)
+        }).await?;
+
+        if !tool_calls.is_empty() {
+            return Ok((Entry::ToolCalls(tool_calls), usage));
+        }
+        Ok((Entry::Assistant(content), usage))
+    }
+}
+
+// Only when there's a schema, so e.g. summaries stay plain text
+fn response_format(format: ResponseFormat, schema: Option<&Value>) -> Option<Value> {
+    let schema = schema?;
+    match format {
+        ResponseFormat::Text => None,
+        ResponseFormat::Json => Some(serde_json::json!({ "type": "json_object" })),
+        ResponseFormat::Schema => Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "assistant_response", "schema": schema, "strict": true },
+        })),
+    }
+}
+
+fn openai_usage(usage: &Value) -> Usage {
+    Usage {
+        prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0),
+        completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0),
+    }
+}
+impl LanguageModel for OpenAi {
+    fn request<'a>(&'a self, messages: &'a [Entry], options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a> {
+        Box::pin(self.complete(messages, options, deltas))
+    }
+}
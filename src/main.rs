@@ -1,21 +1,39 @@
-use std::fs::File;
-use std::io::{BufWriter, Cursor, BufReader};
-use std::sync::{Arc, Mutex};
-use std::time::{Instant, Duration};
-use tokio::process::Command;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
 
-use regex::Regex;
-use anyhow::Result;
-
-// Use cpal for audio input, rodio for output
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{FromSample, Sample, StreamConfig, SampleRate, BufferSize};
-use rodio::{OutputStream, source::Source, Decoder};
+use anyhow::{Result, Context};
 
+use ringbuf::HeapConsumer;
 use serde::{Serialize, Deserialize};
-use webrtc_vad::Vad;
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext};
-use rustpotter::{Rustpotter, RustpotterConfig, Wakeword};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use whisper_rs::WhisperContext;
+
+mod audio;
+use audio::Capture;
+
+mod network;
+
+mod aec;
+use aec::EchoCanceller;
+
+mod denoise;
+use denoise::Denoiser;
+
+mod filters;
+use filters::{Agc, HighPass};
+
+mod output;
+use output::{Output, AudioOutput};
+
+mod satellite;
+
+mod tts;
 
 mod circular_buffer;
 use circular_buffer::CircularBuffer;
@@ -23,200 +41,580 @@ use circular_buffer::CircularBuffer;
 mod chat;
 use chat::{Chat, Entry};
 
-enum SpeakingState {
-    Silent,
-    Speaking,
-    Pending { end: Instant }
-}
+mod cli;
+use cli::{Args, Command};
+use clap::Parser;
+
+mod config;
+use config::{ChatConfig, Config, LlmConfig, PlaybackAction, SttBackend};
+
+mod models;
+
+mod llm;
+use llm::LanguageModels;
+mod ollama;
+mod anthropic;
+#[cfg(feature = "llama")]
+mod llama;
+
+mod transcribe;
+mod cloud_stt;
+mod whisper_server;
+use transcribe::{StreamingTranscriber, Transcription, TranscriptionWorker};
+
+mod sentences;
+use sentences::SentenceStream;
+
+mod tools;
+use tools::Tools;
+mod python;
+mod offline;
+mod camera;
+mod memory;
+mod facts;
+mod cache;
+mod store;
+use store::Store;
+
+mod usage;
+
+mod conversations;
+use conversations::ChatManager;
+
+mod transcript;
+use transcript::{Transcript, AddressMatcher, Rewriter};
+
+mod recording;
+
+mod vad;
+
+mod listener;
+use listener::{Listener, ListenEvent, SpeakingState};
+
+mod enroll;
+
+mod tune;
+
+mod speaker;
+use speaker::SpeakerIdentifier;
+
+#[cfg(feature = "hotkey")]
+mod hotkey;
+
+#[cfg(feature = "gpio")]
+mod gpio;
+
+mod wakeword;
+#[cfg(feature = "openwakeword")]
+mod openwakeword;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut chat = Chat::new();
-    chat.push_system(r#"
-    You are a helpful audio-based assistant. You answer to 'computer' and 'peter', but your real name is 'Grenouille'.
-
-    The user input will be based on STT (speech-to-text) audio input, and may not be completely accurate.
-    If required, you can interface with a Python 3.5 interpreter to assist in answering queries.
-    The python output will be shown after the response.
-
-    Format your response as JSON, here are the possible responses:
-    {
-        "type": "response",
-        "response": "Here is an example response."
+    let args = Args::parse();
+    let mut config = Config::load_or_create(&args.config)?;
+    args.apply(&mut config)?;
+    config.llm.cloud.resolve_api_key();
+    if let Some(local) = &mut config.llm.local {
+        local.resolve_api_key();
+    }
+    config.memory.embedding.resolve_api_key();
+    let config = config;
+    let store = match &config.store.path {
+        Some(path) => Some(Arc::new(Store::open(path)?)),
+        None => None,
+    };
+
+    if let Some(command) = &args.command {
+        return match command {
+            Command::TrainWakeword { dir, name, output } => {
+                let name = name.clone().unwrap_or_else(|| config.wakeword.name.clone());
+                let output = output.clone().unwrap_or_else(|| format!("{}.rpw", name).into());
+                wakeword::train(&name, dir, &output)
+            },
+            Command::RecordWakeword { dir, count } => enroll::record_wakeword(&config, dir, *count).await,
+            Command::EnrollSpeaker { name, dir, count } => {
+                let dir = dir.clone().unwrap_or_else(|| Path::new("speakers").join(name.to_lowercase()));
+                enroll::enroll_speaker(&config, name, &dir, *count).await
+            },
+            Command::TuneWakeword { count, background_seconds } => tune::tune(&config, *count, *background_seconds).await,
+            Command::StoreApiKey { backend } => store_api_key(backend.as_deref().unwrap_or(&config.llm.cloud.name)),
+            Command::ExportChat { path, wakeword, speaker, satellite } => {
+                let key = (wakeword.clone().unwrap_or_else(|| config.wakeword.name.clone()), speaker.clone(), None, satellite.clone());
+                conversations::export(&config, store.as_deref(), &key, path)
+            },
+            Command::ImportChat { path, wakeword, speaker, satellite } => {
+                let key = (wakeword.clone().unwrap_or_else(|| config.wakeword.name.clone()), speaker.clone(), None, satellite.clone());
+                conversations::import(&config, store.as_deref(), &key, path)
+            },
+        };
     }
-    {
-        "type": "python",
-        "response": "The answer to your question is: ",
-        "python": "print(5 + 5)",
+
+    // Satellites only capture and play audio, everything else happens on the server
+    if let Some(server) = &args.satellite {
+        return satellite::run(server, &config).await;
+    }
+
+    let missing = config.missing_resources();
+    if !missing.is_empty() {
+        eprintln!("Missing:");
+        for resource in &missing {
+            eprintln!(" - {}", resource);
+        }
+        anyhow::bail!("Set these up (see config.toml) and run again");
     }
 
-    To review, here are the fields you can use:
-    - type: Can be either 'response' or 'python'
-    - response: The response as a string. Keep responses short and to the point.
-    - python: If type is python, then the python command to run. Do not use any external dependencies when running python.
+    match recording::apply_retention(&config.recording, store.as_deref()) {
+        Ok(0) => (),
+        Ok(removed) => println!("Removed {} old recordings", removed),
+        Err(err) => eprintln!("Failed to clean up recordings: {:?}", err),
+    }
 
-    Provide your answer in JSON form. Reply with only the answer in JSON form and include no other commentary:
-    "#);
-    chat.push_assistant(r#"{"type": "response", "response": "Alright, let's get started!"}"#);
-    chat.push_user(format!(r#"{{"type": "user", "content": "{}"}}"#, "fje and the ant and joke"));
-    chat.push_assistant(r#"{"type": "unclear", "response": "Sorry I'm not sure what you just said there. Can you rephrase that or provide more info?"}"#);
+    let whisper_ctx = match config.stt.backend {
+        SttBackend::Whisper => {
+            println!("Setting up whisper...");
+            let model_path = models::whisper_model(&config.whisper).await?;
+            Some(Arc::new(transcribe::load(&model_path.to_string_lossy(), &config.whisper)?))
+        },
+        _ => None,
+    };
 
-    println!("Setting up whisper...");
+    let language_models = Arc::new(LanguageModels::new(&config.llm, &config.usage, &config.memory, &config.facts, &config.cache, store).await?);
 
-    let whisper_ctx = WhisperContext::new("../ggml-model-whisper-small.en-q5_1.bin").expect("Failed to load model");
-    //let whisper_ctx = WhisperContext::new("../ggml-tiny.en-q4_0.bin").expect("Failed to load model");
-    let mut whisper_state = whisper_ctx.create_state().expect("Failed to create state");
+    if let Some(bind) = &args.server {
+        return serve(bind, Arc::new(config), whisper_ctx, language_models).await;
+    }
 
     println!("Setting up audio...");
 
     let host = cpal::default_host();
-    let input_device = host.default_input_device().unwrap();
-    let output_device = host.default_output_device().unwrap();
-
-    // https://github.com/RustAudio/rodio/issues/330
-    let (_output_stream, output_stream_handle) = OutputStream::try_from_device(&output_device).unwrap();
-    let sink = rodio::Sink::try_new(&output_stream_handle).expect("Sink open failed");
-    let play_file = |path: &str| {
-        sink.append(Decoder::new(
-            File::open(path).unwrap()
-        ).expect("Failed to decode file"));
-    };
+    let output_device = audio::output_device(&host, config.audio.output_device.as_deref())?;
 
-    println!(" - {:?}", input_device.default_input_config());
-    println!(" - {:?}", input_device.name());
+    let (output, echo_reference) = Output::new(&output_device)?;
+    let capture = Capture::start(&host, &config.audio, config.debug.verbosity)?;
 
-    let config: StreamConfig = input_device.default_input_config()?.into();
-    let channel_count = config.channels as usize;
-    let sample_rate = config.sample_rate.0;
+    run(
+        &config,
+        None,
+        whisper_ctx.as_ref(),
+        &language_models,
+        capture,
+        || Capture::start(&host, &config.audio, config.debug.verbosity),
+        &output,
+        echo_reference,
+    ).await
+}
 
-    // Buffer all audio data for the last 15 seconds
-    let audio_buffer: Arc<Mutex<CircularBuffer<f32>>> = Arc::new(Mutex::new(CircularBuffer::new(sample_rate as usize * 15)));
+// Accept satellites, each gets its own session (chat, wakeword detector, ...)
+// running alongside the others
+async fn serve(bind: &str, config: Arc<Config>, whisper_ctx: Option<Arc<WhisperContext>>, language_models: Arc<LanguageModels>) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    println!("Waiting for satellites on {}", listener.local_addr()?);
 
-    let vad_frame_length = (sample_rate as f32 * (10./1000.)) as usize;
-    assert!(vad_frame_length == 160);
-    let mut vad_buffer = [0.; (16000. * (10./1000.)) as usize];
-    let mut vad_i16_buffer = [0i16; (16000. * (10./1000.)) as usize];
-    let mut vad = Vad::new_with_rate_and_mode(webrtc_vad::SampleRate::Rate16kHz, webrtc_vad::VadMode::VeryAggressive);
+    let sessions = tokio::task::LocalSet::new();
+    sessions.run_until(async move {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            let config = config.clone();
+            let whisper_ctx = whisper_ctx.clone();
+            let language_models = language_models.clone();
+            tokio::task::spawn_local(async move {
+                let result = match satellite::accept(stream).await {
+                    Ok((name, capture, output, echo_reference)) => {
+                        println!("Satellite {:?} connected from {}", name, address);
+                        let result = run(
+                            &config,
+                            Some(&name),
+                            whisper_ctx.as_ref(),
+                            &language_models,
+                            capture,
+                            || anyhow::bail!("Satellite disconnected"),
+                            &output,
+                            echo_reference,
+                        ).await;
+                        println!("Satellite {:?} disconnected", name);
+                        result
+                    },
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = &result {
+                    eprintln!("Satellite session from {} failed: {:?}", address, err);
+                }
+                result
+            });
+        }
+    }).await
+}
 
-    let mut rustpotter_config = RustpotterConfig::default();
-    let mut rustpotter = Rustpotter::new(&rustpotter_config).unwrap();
-    rustpotter.add_wakeword(Wakeword::new_from_sample_files(
-        "computer".to_string(), Some(0.5), Some(0.15),
-        (0..=4).map(|i| format!("./clips/{}.wav", i)).collect::<Vec<String>>()
-    ).expect("Failed to add wakeword"));
-    println!("samples per frame {:?}", rustpotter.get_samples_per_frame());
+#[cfg(feature = "keyring")]
+fn store_api_key(backend: &str) -> Result<()> {
+    println!("API key for {:?}:", backend);
+    let mut key = String::new();
+    std::io::stdin().read_line(&mut key)?;
+    keyring::Entry::new(chat::KEYRING_SERVICE, backend)?.set_password(key.trim())?;
+    println!("Saved to the keyring");
+    Ok(())
+}
+#[cfg(not(feature = "keyring"))]
+fn store_api_key(_backend: &str) -> Result<()> {
+    anyhow::bail!("Built without keyring support, rebuild with --features keyring")
+}
 
-    let stream_handle = audio_buffer.clone();
-    let stream = input_device.build_input_stream(
-        &config,
-        move |data: &[f32], _| {
-            data
-                .iter().cloned().enumerate()
-                .filter(|(i, _)| i % channel_count == 0).map(|(_, sample)| sample) // Just grab the first channel
-                .for_each(|sample| {
-                    stream_handle.lock().unwrap().overwrite(sample)
-                });
-        },
-        move |err| {
-            eprintln!("Stream error: {:?}", err);
-        },
-        None
-    )?;
+// The whole pipeline for one input and output, until the input ends
+#[allow(clippy::too_many_arguments)]
+async fn run<O: AudioOutput>(
+    config: &Config,
+    satellite: Option<&str>,
+    whisper_ctx: Option<&Arc<WhisperContext>>,
+    language_models: &LanguageModels,
+    mut capture: Capture,
+    restart_capture: impl Fn() -> Result<Capture>,
+    output: &O,
+    mut echo_reference: HeapConsumer<f32>,
+) -> Result<()> {
+    // A chat per wakeword and speaker, whichever wakeword was heard last gets
+    // the command
+    let wakewords: Vec<String> = std::iter::once(config.wakeword.name.clone())
+        .chain(config.personas.iter().map(|persona| persona.wakeword.clone()))
+        .collect();
+    let chats = ChatManager::new(config, &wakewords, satellite, language_models.store.clone());
+    let mut active_wakeword = config.wakeword.name.clone();
+    // Lent to the command being processed while it runs
+    let mut chats = Some(chats);
+
+    // Commands can also be addressed by persona name
+    let mut address = config.address.clone();
+    address.terms.extend(config.personas.iter().map(|persona| persona.wakeword.clone()));
+    let tools = Tools::new(&config.tools, language_models.store.clone())?;
+    let processor = CommandProcessor {
+        config,
+        output,
+        language_models,
+        tools: &tools,
+        transcriber: TranscriptionWorker::new(config, whisper_ctx.cloned())?,
+        address_matcher: AddressMatcher::new(&address).expect("Invalid address terms"),
+        rewriter: Rewriter::new(&config.transcript).expect("Invalid transcript rewrites"),
+        speakers: SpeakerIdentifier::new(&config.speaker, config.debug.verbosity)?,
+    };
+    let mut streaming = whisper_ctx
+        .filter(|_| config.whisper.streaming)
+        .map(|whisper_ctx| StreamingTranscriber::new(whisper_ctx.clone(), &config.whisper));
+    let play_file = |path: &str| output.play_file(path);
 
-    stream.play().expect("Failed to start audio input stream");
+    let sample_rate = audio::SAMPLE_RATE;
+    let mut capture_retry = Instant::now();
+
+    let mut highpass = config.audio.highpass_hz.map(|cutoff| HighPass::new(cutoff, sample_rate));
+    let mut echo_canceller = config.aec.enabled.then(|| EchoCanceller::new(&config.aec, sample_rate));
+    let mut denoiser = config.audio.denoise.then(Denoiser::new);
+    let mut agc = config.agc.enabled.then(|| Agc::new(&config.agc, sample_rate));
+
+    // Buffer all audio data for the last few seconds, at least enough to rewind
+    // to the start of the wakeword
+    let mut audio_buffer: CircularBuffer<f32> = CircularBuffer::new((sample_rate as f32 * config.audio.buffer_seconds.max(2.)) as usize);
+    let buffer_duration = Duration::from_secs_f32(audio_buffer.capacity() as f32 / sample_rate as f32);
+    println!("Audio history: {:?} ({} samples, {} KiB)",
+        buffer_duration,
+        audio_buffer.capacity(),
+        audio_buffer.capacity() * std::mem::size_of::<f32>() / 1024,
+    );
+
+    let mut vad = vad::create(&config.vad).expect("Failed to set up VAD");
+
+    let mut detector = wakeword::create(&config.wakeword, &config.personas).expect("Failed to set up wakeword detector");
+    let detector_frame_length = detector.samples_per_frame();
+    if config.debug.verbosity >= 1 {
+        println!("samples per frame {:?}", detector_frame_length);
+    }
+    // Detectors get consecutive frames, only while waiting for the wakeword
+    let mut detector_pending: Vec<f32> = Vec::new();
+    // Command words like "stop" only run while an answer is playing
+    let mut command_detector = wakeword::create_commands(&config.wakeword, &config.playback.commands)
+        .expect("Failed to set up playback command detector");
+    let mut command_pending: Vec<f32> = Vec::new();
+    let mut volume: f32 = 1.;
     
-    let mut speaking = SpeakingState::Silent;
-    let mut speaking_start = Instant::now();
-    let mut detection_start = Instant::now();
+    // The listener goes by how much audio came in rather than the wall clock,
+    // so a file replayed at input_file_speed times out the same as live input
+    let clock_start = Instant::now();
+    let mut consumed_samples: u64 = 0;
+    let audio_clock = |consumed_samples: u64| clock_start + Duration::from_secs_f64(consumed_samples as f64 / sample_rate as f64);
+    let mut listener = Listener::new(config.timing.clone(), clock_start);
+    // Everything heard since the start of the command, grows for as long as
+    // the user keeps talking
+    let mut utterance: Option<Vec<f32>> = None;
+    let mut partial_cued = false;
+
+    // Push-to-talk triggers, each reporting presses and releases
+    #[cfg_attr(not(any(feature = "hotkey", feature = "gpio")), allow(unused_mut))]
+    let mut triggers: Vec<std::sync::mpsc::Receiver<bool>> = Vec::new();
+    if let Some(key) = &config.push_to_talk.key {
+        #[cfg(feature = "hotkey")]
+        triggers.push(hotkey::listen(key)?);
+        #[cfg(not(feature = "hotkey"))]
+        anyhow::bail!("Built without push-to-talk support for {:?}, rebuild with --features hotkey", key);
+    }
+    if let Some(pin) = config.gpio.button_pin {
+        #[cfg(feature = "gpio")]
+        triggers.push(gpio::listen(pin)?);
+        #[cfg(not(feature = "gpio"))]
+        anyhow::bail!("Built without GPIO support for pin {}, rebuild with --features gpio", pin);
+    }
+    #[cfg(feature = "gpio")]
+    let mut led = config.gpio.led_pin.map(gpio::Led::new).transpose()?;
+    #[cfg(not(feature = "gpio"))]
+    if let Some(pin) = config.gpio.led_pin {
+        anyhow::bail!("Built without GPIO support for pin {}, rebuild with --features gpio", pin);
+    }
+    let mut push_to_talk_active = false;
+    // An answer or re-prompt is playing, listen this long for a reply once
+    // it's done
+    let mut follow_up_pending: Option<Duration> = None;
+    let mut follow_up_active = false;
+    // Finished commands are transcribed and answered one at a time, while the
+    // loop keeps listening for the next one
+    let mut commands: VecDeque<FinishedCommand> = VecDeque::new();
+    let mut processing: Option<Processing> = None;
     loop {
-        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-        let mut audio_handle = audio_buffer.lock().unwrap();
-
-        if audio_handle.len() > vad_frame_length && audio_handle.len() > 480 {
-            let slices = audio_handle.as_slices(); // I think this works
-            let (left, right) = vad_buffer.split_at_mut(vad_frame_length.saturating_sub(slices.1.len()));
-            right.copy_from_slice(&slices.1[slices.1.len().saturating_sub(right.len())..]);
-            left.copy_from_slice(&slices.0[slices.0.len().saturating_sub(left.len())..]);
-
-            vad_i16_buffer = std::array::from_fn(|i| (vad_buffer[i].clamp(-1., 1.) * i16::MAX as f32) as i16);
-            let voice_segment = vad.is_voice_segment(&vad_i16_buffer).expect("VAD failed");
-
-            match speaking {
-                SpeakingState::Silent => {
-                    let mut rustpotter_buffer = [0.; 480];
-
-                    let (left, right) = rustpotter_buffer.split_at_mut(480_usize.saturating_sub(slices.1.len()));
-                    right.copy_from_slice(&slices.1[slices.1.len().saturating_sub(right.len())..]);
-                    left.copy_from_slice(&slices.0[slices.0.len().saturating_sub(left.len())..]);
-
-                    if let Some(detection) = rustpotter.process_f32(&rustpotter_buffer) {
-                        println!("Rustpotter: {:?}", detection);
-                        speaking = SpeakingState::Speaking;
-                        speaking_start = Instant::now() - Duration::from_millis(2000); // Rustpotter is about 2 seconds slower than the start of the utterance
-                        detection_start = Instant::now();
-                    }
+        let tick = tokio::time::sleep(Duration::from_millis(10));
+        let processed = match &mut processing {
+            Some(command) => tokio::select! {
+                processed = command => Some(processed),
+                _ = tick => None,
+            },
+            None => {
+                tick.await;
+                None
+            }
+        };
+        if let Some((returned, processed)) = processed {
+            processing = None;
+            chats = Some(returned);
+            follow_up_pending = match processed? {
+                Processed::Done => None,
+                Processed::Answered => Some(Duration::from_millis(config.timing.follow_up_ms)),
+                Processed::Reprompted => Some(Duration::from_millis(config.stt.reprompt_listen_ms)),
+            };
+        }
+        for label in tools.due_timers() {
+            println!("Timer {:?} is done", label);
+            if !speak(&format!("Your {} timer is done.", label), config, output).await {
+                play_file(&config.sounds.done);
+            }
+        }
+        if processing.is_none() {
+            if let Some(command) = commands.pop_front() {
+                let chats = chats.take().unwrap();
+                processing = Some(Box::pin(processor.process(chats, command)));
+            }
+        }
+
+        if capture.ended() && listener.is_silent() && processing.is_none() && commands.is_empty() {
+            println!("Input ended");
+            break;
+        }
+
+        // Device disconnected, keep trying to bring it (or the default) back
+        if capture.failed() && Instant::now() > capture_retry {
+            match restart_capture() {
+                Ok(restarted) => {
+                    println!("Audio input restarted");
+                    capture = restarted;
+                    audio_buffer.clear();
                 },
-                SpeakingState::Speaking => {
-                    if !voice_segment {
-                        speaking = SpeakingState::Pending { end: Instant::now() }
+                Err(err) => {
+                    eprintln!("Failed to restart audio input: {:?}", err);
+                    capture_retry = Instant::now() + Duration::from_secs(2);
+                }
+            }
+        }
+        let mut samples: Vec<f32> = capture.consumer.pop_iter().collect();
+        consumed_samples += samples.len() as u64;
+        if let Some(highpass) = &mut highpass {
+            samples.iter_mut().for_each(|sample| *sample = highpass.process(*sample));
+        }
+        if let Some(echo_canceller) = &mut echo_canceller {
+            for sample in samples.iter_mut() {
+                *sample = echo_canceller.process(*sample, echo_reference.pop().unwrap_or(0.));
+            }
+        } else {
+            echo_reference.clear();
+        }
+        if let Some(denoiser) = &mut denoiser {
+            samples = denoiser.process(&samples);
+        }
+        if let Some(agc) = &mut agc {
+            samples.iter_mut().for_each(|sample| *sample = agc.process(*sample));
+        }
+        if let Some(utterance) = &mut utterance {
+            utterance.extend_from_slice(&samples);
+        }
+        let voice_segment = vad.process(&samples).expect("VAD failed");
+        let energy = if samples.is_empty() {
+            0.
+        } else {
+            (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        if listener.is_silent() && !config.push_to_talk.disable_wakeword {
+            detector_pending.extend_from_slice(&samples);
+        } else {
+            detector_pending.clear();
+        }
+        if let Some(command_detector) = &mut command_detector {
+            if output.is_playing() && listener.is_silent() {
+                command_pending.extend_from_slice(&samples);
+                let frame_length = command_detector.samples_per_frame();
+                while command_pending.len() >= frame_length {
+                    let frame: Vec<f32> = command_pending.drain(..frame_length).collect();
+                    let Some(detection) = command_detector.process(&frame) else {
+                        continue;
+                    };
+                    let Some(command) = config.playback.commands.iter().find(|command| command.word == detection.name) else {
+                        continue;
+                    };
+                    println!("Playback command: {:?}", command.word);
+                    match command.action {
+                        PlaybackAction::Stop => {
+                            output.stop();
+                            follow_up_pending = None;
+                            command_pending.clear();
+                        },
+                        PlaybackAction::Louder => {
+                            volume = (volume + config.playback.volume_step).min(2.);
+                            output.set_volume(volume);
+                        },
+                        PlaybackAction::Quieter => {
+                            volume = (volume - config.playback.volume_step).max(0.);
+                            output.set_volume(volume);
+                        },
                     }
-                },
-                SpeakingState::Pending { end } => {
-                    if voice_segment {
-                        speaking = SpeakingState::Speaking;
-                    } else {
-                        if Instant::now() - end > Duration::from_millis(800) && Instant::now() - detection_start > Duration::from_millis(1500) {
-                            speaking = SpeakingState::Silent;
-
-                            play_file("./on.wav");
-
-                            let speaking_duration = Instant::now() - speaking_start;
-                            let speaking_duration_samples = (speaking_duration.as_secs_f32() * sample_rate as f32).ceil() as usize;
-                            println!("Processing, spoke for {:?}", speaking_duration);
-                            //stream.pause().expect("Failed to pause");
-
-                            audio_handle.make_contiguous();
-                            let speaking_slice = &audio_handle.as_slices().0[audio_handle.len().saturating_sub(speaking_duration_samples)..];
-
-                            let mut whisper_params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
-                            whisper_params.set_print_progress(false);
-                            whisper_params.set_suppress_non_speech_tokens(true);
-                            let whisper_processing_start = Instant::now();
-                            whisper_state.full(whisper_params, speaking_slice).expect("Failed to run whisper model");
-
-                            let num_segments = whisper_state
-                                .full_n_segments()
-                                .expect("Failed to get whisper segment count");
-                            let segments: Vec<_> = (0..num_segments).map(|i| {
-                                let segment_text = whisper_state.full_get_segment_text(i).expect("Failed to get whisper segment");
-                                strip_brackets(&segment_text.trim().to_lowercase())
-                            }).collect();
-                            
-                            println!("Finished processing, took {:?} | {:?}x faster than realtime",
-                                Instant::now() - whisper_processing_start,
-                                speaking_duration.as_secs_f32()/(Instant::now() - whisper_processing_start).as_secs_f32(),
-                            );
-                            play_file("./done.wav");
-
-                            let response = handle_prompt(&mut chat, segments).await?;
-                            match response {
-                                Some(r) => {
-                                    println!("Response {:?}: ", r);
-
-                                    if matches!(r.ty, ResponseType::Response) && r.response.is_some() {
-                                        play_tts(&r.response.unwrap()).await;
-                                    } else {
-                                        play_file("./unclear.wav");
-                                    }
-                                },
-                                None => play_file("./unclear.wav")
-                            }
+                }
+            } else {
+                command_pending.clear();
+            }
+        }
+        samples.into_iter().for_each(|sample| audio_buffer.overwrite(sample));
+
+        // Push-to-talk starts and ends commands on its own
+        let mut push_to_talk_finished = false;
+        for trigger in &triggers {
+            while let Ok(pressed) = trigger.try_recv() {
+                let now = audio_clock(consumed_samples);
+                let (start, stop) = if config.push_to_talk.toggle {
+                    (pressed && !push_to_talk_active, pressed && push_to_talk_active)
+                } else {
+                    (pressed, !pressed && push_to_talk_active)
+                };
+                if start && (listener.is_silent() || listener.is_follow_up()) {
+                    println!("Push-to-talk");
+                    output.stop();
+                    output.duck(config.audio.ducking);
+                    utterance = Some(Vec::new());
+                    push_to_talk_active = true;
+                    listener.handle(ListenEvent::Start, now);
+                } else if stop {
+                    push_to_talk_finished = listener.handle(ListenEvent::Stop, now);
+                }
+            }
+        }
 
-                            audio_handle.clear();
-                            //stream.play().expect("Failed to play");
+        if follow_up_pending.is_some() && !output.is_playing() {
+            let length = follow_up_pending.take().unwrap();
+            listener.handle(ListenEvent::FollowUp { length }, audio_clock(consumed_samples));
+        }
+
+        if audio_buffer.len() > detector_frame_length {
+            let now = audio_clock(consumed_samples);
+            let finished = push_to_talk_finished || if listener.is_silent() {
+                let mut detection = None;
+                while detector_pending.len() >= detector_frame_length && detection.is_none() {
+                    let frame: Vec<f32> = detector_pending.drain(..detector_frame_length).collect();
+                    detection = detector.process(&frame);
+                }
+
+                if let Some(detection) = detection {
+                    println!("Wakeword: {:?}", detection);
+                    if wakewords.contains(&detection.name) {
+                        active_wakeword = detection.name.clone();
+                    }
+                    if config.wakeword.barge_in && output.is_playing() {
+                        println!("Interrupting playback");
+                        output.stop();
+                    }
+                    partial_cued = false;
+                    output.duck(config.audio.ducking);
+                    // Back past anything not fed to the detector yet, the frames that
+                    // scored, and the wakeword itself
+                    let rewind = detector_pending.len() +
+                        detection.counter * detector_frame_length +
+                        sample_rate as usize * config.wakeword.length_ms as usize / 1000;
+                    let rewind = rewind.min(audio_buffer.len());
+                    utterance = Some(audio_buffer.iter().skip(audio_buffer.len() - rewind).copied().collect());
+                    listener.handle(ListenEvent::Wakeword, now)
+                } else {
+                    if config.wakeword.partial_cue {
+                        match detector.partial() {
+                            Some(partial) if !partial_cued && partial.score >= config.wakeword.partial_cue_threshold => {
+                                if config.debug.verbosity >= 2 {
+                                    println!("Partial wakeword: {:?}", partial);
+                                }
+                                play_file(&config.wakeword.partial_cue_sound);
+                                partial_cued = true;
+                            },
+                            None => partial_cued = false,
+                            _ => {}
                         }
                     }
+                    false
+                }
+            } else {
+                let waiting_for_reply = listener.is_follow_up();
+                let was_speaking = listener.state() == SpeakingState::Speaking;
+                let finished = listener.handle(ListenEvent::Frame { voice: voice_segment, energy }, now);
+                if was_speaking && matches!(listener.state(), SpeakingState::Pending { .. }) {
+                    if let (Some(streaming), Some(utterance)) = (&mut streaming, &utterance) {
+                        streaming.pause(utterance);
+                    }
+                }
+                if waiting_for_reply && !listener.is_silent() && !listener.is_follow_up() {
+                    println!("Follow-up");
+                    follow_up_active = true;
+                    output.duck(config.audio.ducking);
+                    // The VAD takes a moment to trigger, keep the start of the first word
+                    let rewind = (sample_rate as usize / 4).min(audio_buffer.len());
+                    utterance = Some(audio_buffer.iter().skip(audio_buffer.len() - rewind).copied().collect());
+                }
+                finished
+            };
+            #[cfg(feature = "gpio")]
+            if let Some(led) = &mut led {
+                led.set(!listener.is_silent());
+            }
+
+            if finished {
+                let push_to_talk_command = std::mem::replace(&mut push_to_talk_active, false);
+                let follow_up_command = std::mem::replace(&mut follow_up_active, false);
+                output.restore();
+                play_file(&config.sounds.processing);
+
+                // Only the tail is left when the rest was transcribed while speaking
+                let (streamed, transcribed) = match &mut streaming {
+                    Some(streaming) => streaming.finish().await.unwrap_or_else(|err| {
+                        eprintln!("{:?}, transcribing the whole command", err);
+                        (Transcription::default(), 0)
+                    }),
+                    None => (Transcription::default(), 0),
+                };
+                if processing.is_some() {
+                    println!("Queued, still working on the last command");
                 }
+                commands.push_back(FinishedCommand {
+                    samples: utterance.take().unwrap_or_default(),
+                    streamed,
+                    transcribed,
+                    wakeword: active_wakeword.clone(),
+                    push_to_talk: push_to_talk_command,
+                    follow_up: follow_up_command,
+                });
             }
         }
     }
@@ -224,6 +622,301 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+// Kept around the speech when trimming a command to it
+const SPEECH_PADDING_MS: usize = 200;
+
+// A command that's been spoken, waiting to be transcribed and answered
+struct FinishedCommand {
+    samples: Vec<f32>,
+    // Already transcribed while it was spoken, covering samples[..transcribed]
+    streamed: Transcription,
+    transcribed: usize,
+    wakeword: String,
+    push_to_talk: bool,
+    follow_up: bool,
+}
+
+enum Processed {
+    Done,
+    Answered,
+    // The transcription wasn't confident enough, and the user was asked to
+    // say it again
+    Reprompted,
+}
+
+// A command being processed, handing the chats back once it is
+type Processing<'a> = Pin<Box<dyn Future<Output = (ChatManager, Result<Processed>)> + 'a>>;
+
+// Everything that makes sense of a finished command
+struct CommandProcessor<'a, O: AudioOutput> {
+    config: &'a Config,
+    output: &'a O,
+    language_models: &'a LanguageModels,
+    tools: &'a Tools,
+    transcriber: TranscriptionWorker,
+    address_matcher: AddressMatcher,
+    rewriter: Rewriter,
+    speakers: SpeakerIdentifier,
+}
+impl<O: AudioOutput> CommandProcessor<'_, O> {
+    // Transcribes and answers a command alongside the audio loop. Hands the chats
+    // back afterwards, with what was played
+    async fn process(&self, mut chats: ChatManager, command: FinishedCommand) -> (ChatManager, Result<Processed>) {
+        let &CommandProcessor { config, output, language_models, tools, ref transcriber, ref address_matcher, ref rewriter, ref speakers } = self;
+        let processed = async {
+            let sample_rate = audio::SAMPLE_RATE;
+            let play_file = |path: &str| output.play_file(path);
+            let speaking_slice = &command.samples[..];
+            let speaking_duration = Duration::from_secs_f32(speaking_slice.len() as f32 / sample_rate as f32);
+            println!("Processing, spoke for {:?}", speaking_duration);
+
+            let whisper_processing_start = Instant::now();
+            let mut transcription = command.streamed;
+            let tail = match transcriber.transcribe(speaking_slice[command.transcribed..].to_vec()).await {
+                Ok(tail) => tail,
+                Err(err) => {
+                    eprintln!("Failed to transcribe: {:?}", err);
+                    play_file(&config.sounds.unclear);
+                    return Ok(Processed::Done);
+                },
+            };
+            transcription.append(tail.shifted(command.transcribed as f32 / sample_rate as f32));
+            let segments = rewriter.apply(&transcription.segments);
+            let transcript = Transcript::new(&segments).with_language(transcription.language.as_deref());
+
+            println!("Finished processing, took {:?} | {:?}x faster than realtime",
+                Instant::now() - whisper_processing_start,
+                speaking_duration.as_secs_f32()/(Instant::now() - whisper_processing_start).as_secs_f32(),
+            );
+            play_file(&config.sounds.done);
+
+            // Leave out the silence around the speech, when the transcriber
+            // says where it was
+            let speech_slice = match transcription.speech() {
+                Some((start, end)) => {
+                    let padding = sample_rate as usize * SPEECH_PADDING_MS / 1000;
+                    let start = ((start * sample_rate as f32) as usize).saturating_sub(padding).min(speaking_slice.len());
+                    let end = ((end * sample_rate as f32) as usize + padding).clamp(start, speaking_slice.len());
+                    &speaking_slice[start..end]
+                },
+                None => speaking_slice,
+            };
+            let speech_duration = Duration::from_secs_f32(speech_slice.len() as f32 / sample_rate as f32);
+            if speech_duration != speaking_duration {
+                println!("Speech took {:?} of it", speech_duration);
+            }
+            if let Some(dir) = &config.recording.dir {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+                let path = Path::new(dir).join(format!("{}.wav", timestamp));
+                let saved = recording::write_wav(&path, speech_slice, sample_rate, &config.recording)
+                    .with_context(|| format!("Failed to save utterance to {:?}", path))
+                    .and_then(|()| recording::write_transcript(&path, speech_duration, &segments, transcript.original())
+                        .with_context(|| format!("Failed to save transcript for {:?}", path)));
+                if let Err(err) = saved {
+                    eprintln!("{:?}", err);
+                }
+            }
+
+            if let Some(confidence) = transcription.confidence {
+                if confidence < config.stt.min_confidence && !transcript.normalized().is_empty() {
+                    println!("Not sure what was said ({:.2} confidence), asking again", confidence);
+                    match tts::synthesize(&config.stt.reprompt, &config.tts, &config.performance).await {
+                        Ok(wav) => {
+                            output.play_wav(wav);
+                            return Ok(Processed::Reprompted);
+                        },
+                        Err(err) => {
+                            eprintln!("TTS failed: {:?}", err);
+                            play_file(&config.sounds.unclear);
+                            return Ok(Processed::Done);
+                        }
+                    }
+                }
+            }
+
+            let speaker = speakers.identify(speech_slice);
+            if let Some(speaker) = speaker {
+                println!("Speaking: {}", speaker.name);
+            }
+
+            let whole = Transcript::new(&segments);
+            let addressed = command.push_to_talk || command.follow_up || address_matcher.is_match(&whole);
+            if let Some(store) = language_models.store.as_ref().filter(|_| !whole.normalized().is_empty()) {
+                let logged = store.log_transcript(&command.wakeword, speaker.map(|speaker| speaker.name.as_str()), &whole.for_display(&config.transcript), addressed);
+                if let Err(err) = logged {
+                    eprintln!("Failed to save the transcript: {:?}", err);
+                }
+            }
+            if let Some(name) = addressed.then(|| chats.switch(config, &whole)).flatten() {
+                println!("Switched to the {:?} conversation", name);
+                if speak(&config.chat.switch_response.replace("{name}", &name), config, output).await {
+                    return Ok(Processed::Answered);
+                }
+                play_file(&config.sounds.done);
+                return Ok(Processed::Done);
+            }
+            let key = chats.key(&command.wakeword, speaker);
+            let chat = chats.get(config, &key, speaker);
+            chat.set_speaker(speaker.map(|speaker| speaker.name.as_str()));
+
+            // A long pause followed by addressing the assistant again starts
+            // another command
+            let split_pause = config.whisper.split_pause_ms as f32 / 1000.;
+            let mut commands: Vec<&[String]> = Vec::new();
+            let mut start = 0;
+            for i in 1..segments.len() {
+                let paused = transcription.is_timed() && split_pause > 0. &&
+                    transcription.times[i].0 - transcription.times[i - 1].1 >= split_pause;
+                if paused && address_matcher.is_match(&Transcript::new(&segments[i..=i])) {
+                    commands.push(&segments[start..i]);
+                    start = i;
+                }
+            }
+            commands.push(&segments[start..]);
+            if commands.len() > 1 {
+                println!("Heard {} commands in one", commands.len());
+            }
+
+            let mut answered = false;
+            for (i, segments) in commands.into_iter().enumerate() {
+                let transcript = Transcript::new(segments).with_language(transcription.language.as_deref());
+                if answered {
+                    output::wait_until_done(output).await;
+                }
+                // Holding the key down is as good as saying the wakeword, and a
+                // follow-up continues the conversation
+                let addressed = (i == 0 && command.push_to_talk) ||
+                    (i == 0 && command.follow_up && !transcript.normalized().is_empty()) ||
+                    address_matcher.is_match(&transcript);
+                // With llm.stream, whole sentences are spoken as they come in.
+                // Each attempt at an answer (the local model, then the cloud)
+                // streams on its own, one given up on stops where it was
+                let (attempts, mut attempt_receiver) = tokio::sync::mpsc::unbounded_channel::<UnboundedReceiver<String>>();
+                let attempts = config.llm.stream.then_some(attempts);
+                let speak_sentences = async {
+                    // What the last attempt said
+                    let mut spoken = String::new();
+                    let mut attempt = attempt_receiver.recv().await;
+                    while let Some(mut deltas) = attempt {
+                        let mut sentences = SentenceStream::new();
+                        spoken.clear();
+                        while let Some(delta) = deltas.recv().await {
+                            for sentence in sentences.push(&delta) {
+                                if speak(&sentence, config, output).await {
+                                    spoken.push_str(&sentence);
+                                    spoken.push(' ');
+                                }
+                            }
+                        }
+                        attempt = attempt_receiver.recv().await;
+                        if attempt.is_none() {
+                            for sentence in sentences.finish() {
+                                if speak(&sentence, config, output).await {
+                                    spoken.push_str(&sentence);
+                                    spoken.push(' ');
+                                }
+                            }
+                        }
+                    }
+                    spoken
+                };
+                let (response, spoken) = tokio::join!(handle_prompt(chat, &transcript, addressed, config, language_models, tools, attempts), speak_sentences);
+                let mut response = response?;
+                // Only when what was said is the answer that was kept, otherwise
+                // (a repaired answer, the offline one) it's said in full below
+                let spoken = !spoken.is_empty() && response.as_ref()
+                    .and_then(|r| r.response.as_deref())
+                    .is_some_and(|text| text.split_whitespace().eq(spoken.split_whitespace()));
+                // A python answer's output is said after its response, and
+                // kept in the chat for follow-ups
+                let mut printed = None;
+                if let Some(r) = response.as_mut().filter(|r| matches!(r.ty, ResponseType::Python)) {
+                    let code = r.python.as_deref().unwrap_or_default();
+                    let result = tools.python(code).await.unwrap_or_else(|err| {
+                        eprintln!("Failed to run the python: {:?}", err);
+                        format!("Error: {:#}", err)
+                    });
+                    println!("Python printed {:?}", result);
+                    chat.push_system(format!("The python printed: {}", result));
+                    r.response = Some(format!("{}{}", r.response.as_deref().unwrap_or_default(), result));
+                    printed = Some(result);
+                }
+                if let (Some(memory), Some(text)) = (&language_models.memory, response.as_ref().and_then(|r| r.response.clone())) {
+                    let memory = memory.clone();
+                    let exchange = format!("User: {}\nAssistant: {}", transcript.for_llm(&config.transcript), text);
+                    tokio::spawn(async move {
+                        if let Err(err) = memory.remember(exchange).await {
+                            eprintln!("Failed to save to memory: {:?}", err);
+                        }
+                    });
+                }
+                if spoken {
+                    if let Some(printed) = &printed {
+                        speak(printed, config, output).await;
+                    }
+                    answered = true;
+                    continue;
+                }
+                match response {
+                    Some(r) => {
+                        println!("Response {:?}: ", r);
+
+                        if let (ResponseType::Response | ResponseType::Python, Some(text)) = (&r.ty, r.response) {
+                            // Lists are read out an item at a time, after
+                            // whatever comes before them
+                            let parts = match Entry::Assistant(text.clone()).as_list() {
+                                Some(items) => {
+                                    let intro = text.lines()
+                                        .take_while(|line| Entry::Assistant(line.to_string()).as_list().is_none())
+                                        .collect::<Vec<_>>()
+                                        .join(" ");
+                                    std::iter::once(intro).filter(|intro| !intro.trim().is_empty()).chain(items).collect()
+                                },
+                                None => vec![text],
+                            };
+                            for part in parts {
+                                match tts::synthesize(&part, &config.tts, &config.performance).await {
+                                    Ok(wav) => {
+                                        output.play_wav(wav);
+                                        answered = true;
+                                    },
+                                    Err(err) => {
+                                        eprintln!("TTS failed: {:?}", err);
+                                        play_file(&config.sounds.unclear);
+                                        break;
+                                    }
+                                }
+                            }
+                        } else {
+                            play_file(&config.sounds.unclear);
+                        }
+                    },
+                    None => play_file(&config.sounds.unclear)
+                }
+            }
+            // Once the answer is on its way, fold old turns into a summary
+            if let Some(summarize_after) = config.chat.summarize_after {
+                if chat.history_len() > summarize_after {
+                    let model = language_models.local.as_ref().unwrap_or(&language_models.cloud);
+                    match chat.summarize(model.as_ref(), summarize_after / 2).await {
+                        Ok(()) => println!("Summarized the older turns"),
+                        Err(err) => eprintln!("Failed to summarize the chat: {:?}", err),
+                    }
+                }
+            }
+            chats.save(config, &key);
+            if answered {
+                if config.performance.serialize_stages {
+                    output::wait_until_done(output).await;
+                }
+                return Ok(Processed::Answered);
+            }
+            Ok(Processed::Done)
+        }.await;
+        (chats, processed)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 enum ResponseType {
@@ -239,35 +932,275 @@ struct AssistantResponse {
     python: Option<String>
 }
 
-async fn handle_prompt(chat: &mut Chat, prompt: Vec<String>) -> Result<Option<AssistantResponse>> {
-    let prompt = prompt.join(" ");
+// AssistantResponse's JSON schema, for backends that can hold replies to it.
+// Strict mode wants every field required, so the unused ones are null
+fn response_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "type": { "type": "string", "enum": ["response", "python", "unclear"] },
+            "response": { "type": ["string", "null"] },
+            "python": { "type": ["string", "null"] },
+        },
+        "required": ["type", "response", "python"],
+        "additionalProperties": false,
+    })
+}
+
+// Sentences go out to TTS as they arrive, returns whether it played
+async fn speak<O: AudioOutput>(sentence: &str, config: &Config, output: &O) -> bool {
+    match tts::synthesize(sentence, &config.tts, &config.performance).await {
+        Ok(wav) => {
+            output.play_wav(wav);
+            true
+        },
+        Err(err) => {
+            eprintln!("TTS failed: {:?}", err);
+            false
+        }
+    }
+}
 
-    println!("Handling prompt: {:?}", prompt);
+// Tool call and result exchanges allowed for a single command
+const MAX_TOOL_ROUNDS: usize = 5;
 
-    let computer_regex = Regex::new("^(computer|peter|[a-zA-Z]+ peter)")?; // Sometimes mistakes 'computer' for 'peter'
-    if computer_regex.is_match(&prompt) {
-        chat.push_user(format!(r#"{{"type": "user", "content": "{}"}}"#, prompt));
-        chat.complete().await?;
+// addressed is whether the transcript is meant for the assistant at all
+// The answer is also streamed to attempts when given
+async fn handle_prompt(
+    chat: &mut Chat,
+    transcript: &Transcript,
+    addressed: bool,
+    config: &Config,
+    language_models: &LanguageModels,
+    tools: &Tools,
+    attempts: Option<Attempts>,
+) -> Result<Option<AssistantResponse>> {
+    println!("Handling prompt: {:?}", transcript.for_display(&config.transcript));
 
-        let json_response = chat.last().unwrap().content().to_string();
-        Ok(serde_json::from_str(&json_response).ok())
+    if addressed && recording::is_purge_request(transcript, &config.recording) {
+        // Handled locally, the request (and audio) shouldn't reach the LLM
+        let response = match recording::purge(&config.recording, language_models.store.as_deref()) {
+            Ok(removed) => format!("Deleted {} recordings.", removed),
+            Err(err) => {
+                eprintln!("Failed to delete recordings: {:?}", err);
+                "Sorry, I couldn't delete the recordings.".to_string()
+            }
+        };
+        return Ok(Some(AssistantResponse { ty: ResponseType::Response, response: Some(response), python: None }));
+    }
+
+    if addressed && config.usage.report_phrases.iter().any(|phrase| transcript.normalized().contains(&phrase.to_lowercase())) {
+        let today = language_models.usage.today();
+        let response = config.usage.report_response
+            .replace("{tokens}", &(today.prompt_tokens + today.completion_tokens).to_string())
+            .replace("{requests}", &today.requests.to_string())
+            .replace("{cost}", &format!("{:.2}", today.cost));
+        return Ok(Some(AssistantResponse { ty: ResponseType::Response, response: Some(response), python: None }));
+    }
+
+    if addressed {
+        if let Some(response) = language_models.facts.handle(transcript) {
+            return Ok(Some(AssistantResponse { ty: ResponseType::Response, response: Some(response), python: None }));
+        }
+    }
+
+    if addressed && is_reset_request(transcript, &config.chat) {
+        chat.reset();
+        println!("Chat reset");
+        return Ok(Some(AssistantResponse { ty: ResponseType::Response, response: Some(config.chat.reset_response.clone()), python: None }));
+    }
+
+    if addressed {
+        // A turn that goes wrong is taken back out, so it doesn't throw off
+        // the ones after it
+        let turn_started = chrono::Utc::now();
+        let undo_turn = |chat: &mut Chat| chat.retain(|_, meta| meta.created.is_none_or(|created| created < turn_started));
+        chat.start_turn();
+        let message = match transcript.language() {
+            Some(language) => serde_json::json!({ "type": "user", "language": language, "content": transcript.for_llm(&config.transcript) }),
+            None => serde_json::json!({ "type": "user", "content": transcript.for_llm(&config.transcript) }),
+        }.to_string();
+        let image = match config.camera.enabled && config.camera.phrases.iter().any(|phrase| transcript.normalized().contains(&phrase.to_lowercase())) {
+            true => {
+                let camera_config = config.camera.clone();
+                tokio::task::spawn_blocking(move || camera::snapshot(&camera_config)).await?
+                    .map_err(|err| eprintln!("Failed to take a snapshot: {:?}", err))
+                    .ok()
+            },
+            false => None,
+        };
+        // Remembered facts and past exchanges like this one are only brought
+        // up for this turn
+        let mut notes: Vec<String> = language_models.facts.note().into_iter().collect();
+        // Recalled exchanges are left out, they'd be different every time
+        let cache_key = language_models.cache.key(transcript, chat.seed(), chat.recent(config.cache.context_entries), &notes)
+            .filter(|_| image.is_none());
+        let recalled = match &language_models.memory {
+            Some(memory) => memory.recall(transcript.for_llm(&config.transcript).as_str()).await
+                .map_err(|err| eprintln!("Failed to search memory: {:?}", err))
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if !recalled.is_empty() {
+            let note = recalled.iter()
+                .map(|item| format!("- {}: {}", item.created.with_timezone(&chrono::Local).format("%A %B %-d %Y"), item.text))
+                .collect::<Vec<_>>()
+                .join("\n");
+            notes.push(format!("Things from past conversations that may be relevant:\n{}", note));
+        }
+        for note in &notes {
+            chat.push_system(note);
+        }
+        match image {
+            Some(image) => chat.push_user_image(message, image),
+            None => chat.push_user(message),
+        }
+        if config.debug.verbosity >= 1 {
+            println!("Sending {} tokens", chat.request_tokens());
+        }
+        let cached = cache_key.and_then(|key| language_models.cache.get(key));
+        let rounds = match &cached {
+            Some(cached) => {
+                println!("Answering from the cache");
+                chat.push_assistant(cached);
+                0
+            },
+            None => MAX_TOOL_ROUNDS,
+        };
+        let mut ran_tools = false;
+        // Tool calls are run and their results sent back until there's an answer
+        for _ in 0..rounds {
+            if let Err(err) = complete(chat, &config.llm, language_models, attempts.as_ref()).await {
+                eprintln!("Failed to get an answer: {:?}", err);
+                undo_turn(chat);
+                // Some things don't need the LLM
+                let response = offline::answer(transcript, &config.offline, tools)
+                    .unwrap_or_else(|| config.llm.unreachable_response.clone());
+                return Ok(Some(AssistantResponse { ty: ResponseType::Response, response: Some(response), python: None }));
+            }
+            let Some(Entry::ToolCalls(calls)) = chat.last().cloned() else { break };
+            for call in &calls {
+                let result = tools.call(call).await;
+                chat.push_tool_result(call, result);
+            }
+            ran_tools = true;
+        }
+
+        let mut response = parse_response(chat.last().unwrap());
+        // Ending on a tool result means the rounds ran out, there's no answer
+        // to repair
+        let repair_attempts = match chat.last() {
+            Some(Entry::Tool { .. }) => 0,
+            _ => config.llm.json_repair_attempts,
+        };
+        for _ in 0..repair_attempts {
+            let Err(err) = &response else { break };
+            println!("Invalid answer ({:#}), asking for it again", err);
+            // Nothing from this turn is trimmed, so it stays where it is
+            let invalid = chat.len() - 1;
+            chat.push_user(format!("Your last reply wasn't valid ({:#}). Resend it as valid JSON in the format from the system prompt, with no other text.", err));
+            if let Err(err) = complete(chat, &config.llm, language_models, None).await {
+                eprintln!("Failed to get an answer: {:?}", err);
+                break;
+            }
+            // Only the repaired answer stays in the history, in place of the
+            // invalid one
+            let repaired = chat.pop().unwrap();
+            chat.truncate_to(invalid);
+            response = parse_response(&repaired);
+            chat.push_entry(repaired);
+        }
+        chat.drop_images();
+        if !notes.is_empty() {
+            chat.retain(|entry, _| !matches!(entry, Entry::System(content) if notes.contains(content)));
+        }
+        if response.is_err() {
+            undo_turn(chat);
+        } else if let Some(key) = cache_key.filter(|_| cached.is_none() && !ran_tools) {
+            language_models.cache.insert(key, chat.last().unwrap().content());
+        }
+        Ok(response.ok())
     } else {
+        if config.debug.log_rejected || config.debug.verbosity >= 1 {
+            if let Err(err) = log_rejected(transcript, config) {
+                eprintln!("Failed to log rejected transcript: {:?}", err);
+            }
+        }
         Ok(None)
     }
 }
-async fn play_tts(text: &str) {
-    Command::new("./mimic.exe")
-        .arg("-voice").arg("kal")
-        .arg("--setf").arg("duration_stretch=0.85")
-        .arg("--setf").arg("int_f0_target_mean=75")
-        .arg(format!(r#""{}""#, text))
-        .spawn().expect("Mimic failed to start")
-        .wait()
-        .await
-        .expect("Mimic failed to run");
+// Checks the answer has the fields its type needs. JSON wrapped in other text
+// is dug out, and a ```python block in a plain text answer is taken as a
+// python answer
+fn parse_response(entry: &Entry) -> Result<AssistantResponse> {
+    let python = || entry.as_code_blocks().into_iter()
+        .find(|(language, _)| matches!(language.as_deref(), Some("python" | "py")))
+        .map(|(_, code)| AssistantResponse { ty: ResponseType::Python, response: None, python: Some(code) });
+    let response: AssistantResponse = match serde_json::from_str(entry.content()) {
+        Ok(response) => response,
+        Err(err) => match python() {
+            Some(response) => response,
+            None => serde_json::from_value(entry.as_json().ok_or(err)?)?,
+        },
+    };
+    match response.ty {
+        ResponseType::Response if response.response.is_none() => anyhow::bail!("A response without \"response\""),
+        ResponseType::Python if response.python.is_none() => anyhow::bail!("A python answer without \"python\""),
+        _ => Ok(response),
+    }
+}
+fn is_reset_request(transcript: &Transcript, config: &ChatConfig) -> bool {
+    config.reset_phrases.iter().any(|phrase| transcript.normalized().contains(&phrase.to_lowercase()))
 }
+fn log_rejected(transcript: &Transcript, config: &Config) -> Result<()> {
+    let text = transcript.for_display(&config.transcript);
+    println!("Rejected (not addressed): {:?}", text);
 
-fn strip_brackets(input: &str) -> String {
-    let re = Regex::new(r"[\[\(].+?[\]\)]").expect("Invalid regex");
-    re.replace_all(input, "").to_string()
-}
\ No newline at end of file
+    if let Some(path) = &config.debug.rejected_log_path {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", text)?;
+    }
+    Ok(())
+}
+// Each attempt at an answer streams its deltas on a channel of its own
+type Attempts = UnboundedSender<UnboundedReceiver<String>>;
+fn start_attempt(attempts: Option<&Attempts>) -> Option<UnboundedSender<String>> {
+    let (deltas, receiver) = tokio::sync::mpsc::unbounded_channel();
+    attempts?.send(receiver).ok()?;
+    Some(deltas)
+}
+
+async fn complete(chat: &mut Chat, config: &LlmConfig, language_models: &LanguageModels, attempts: Option<&Attempts>) -> Result<()> {
+    chat.fit_token_budget();
+    if let (Some(local), Some(local_model)) = (&config.local, &language_models.local) {
+        let timeout = Duration::from_millis(config.local_timeout_ms);
+        // An answer that might be thrown out for the cloud's isn't said early
+        let deltas = start_attempt(attempts.filter(|_| !config.fallback_on_invalid_json));
+        let request = async {
+            match deltas {
+                Some(deltas) => chat.request_stream(local_model.as_ref(), deltas).await,
+                None => chat.request(local_model.as_ref()).await,
+            }
+        };
+        match tokio::time::timeout(timeout, request).await {
+            Ok(Ok((completion, usage))) => {
+                if !config.fallback_on_invalid_json || matches!(completion, Entry::ToolCalls(_)) ||
+                    parse_response(&completion).is_ok() {
+                    println!("Response from {}", local.name);
+                    chat.push_completion(completion, usage);
+                    return Ok(());
+                }
+                println!("{} returned invalid JSON, falling back to {}", local.name, config.cloud.name);
+            },
+            Ok(Err(err)) => println!("{} failed ({:?}), falling back to {}", local.name, err, config.cloud.name),
+            Err(_) => println!("{} timed out, falling back to {}", local.name, config.cloud.name),
+        }
+    }
+
+    match start_attempt(attempts) {
+        Some(deltas) => chat.complete_stream(language_models.cloud.as_ref(), deltas).await?,
+        None => chat.complete(language_models.cloud.as_ref()).await?,
+    };
+    println!("Response from {}", config.cloud.name);
+    Ok(())
+}
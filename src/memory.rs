@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, Context, bail};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+
+use crate::chat::Api;
+use crate::config::MemoryConfig;
+use crate::llm::{Usage, check_status};
+use crate::store::Store;
+use crate::usage::UsageTracker;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryItem {
+    pub text: String,
+    pub created: DateTime<Utc>,
+    embedding: Vec<f32>,
+}
+
+// Past exchanges, embedded so the ones like a new prompt can be brought back
+// up. Kept in the store, or memory.path without one
+pub struct Memory {
+    config: MemoryConfig,
+    client: reqwest::Client,
+    usage: Arc<UsageTracker>,
+    store: Option<Arc<Store>>,
+    items: Mutex<Vec<MemoryItem>>,
+}
+impl Memory {
+    pub fn new(config: &MemoryConfig, client: &reqwest::Client, usage: Arc<UsageTracker>, store: Option<Arc<Store>>) -> Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let items = match &store {
+            Some(store) => store.memories()?.into_iter()
+                .map(|(text, created, embedding)| MemoryItem { text, created, embedding })
+                .collect(),
+            None if path.exists() => {
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read memory {:?}", path))?;
+                serde_json::from_str(&contents).with_context(|| format!("Failed to parse memory {:?}", path))?
+            },
+            None => Vec::new(),
+        };
+        Ok(Memory { config: config.clone(), client: client.clone(), usage, store, items: Mutex::new(items) })
+    }
+
+    // The memory.top_k items most like the query, oldest first
+    pub async fn recall(&self, query: &str) -> Result<Vec<MemoryItem>> {
+        if self.items.lock().unwrap().is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = self.embed(query).await?;
+        let items = self.items.lock().unwrap();
+        let mut scored: Vec<(f32, &MemoryItem)> = items.iter()
+            .map(|item| (cosine_similarity(&query, &item.embedding), item))
+            .filter(|(similarity, _)| *similarity >= self.config.min_similarity)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut recalled: Vec<MemoryItem> = scored.into_iter().take(self.config.top_k).map(|(_, item)| item.clone()).collect();
+        recalled.sort_by_key(|item| item.created);
+        Ok(recalled)
+    }
+
+    pub async fn remember(&self, text: String) -> Result<()> {
+        let embedding = self.embed(&text).await?;
+        let created = Utc::now();
+        if let Some(store) = &self.store {
+            store.add_memory(&text, created, &embedding)?;
+        }
+        let mut items = self.items.lock().unwrap();
+        items.push(MemoryItem { text, created, embedding });
+        match &self.store {
+            Some(_) => Ok(()),
+            None => std::fs::write(&self.config.path, serde_json::to_string(&*items)?)
+                .with_context(|| format!("Failed to save memory {:?}", self.config.path)),
+        }
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let backend = &self.config.embedding;
+        let mut request = self.client.post(backend.embeddings_url());
+        if let Some(key) = &backend.api_key {
+            request = match backend.api {
+                Api::Azure => request.header("api-key", key.trim()),
+                _ => request.bearer_auth(key.trim()),
+            };
+        }
+        let res = request.json(&json!({ "model": backend.model, "input": [text] })).send().await?;
+        let response: Value = check_status(res).await?.json().await?;
+
+        let (embedding, prompt_tokens) = match backend.api {
+            Api::OpenAi | Api::Azure => (response.pointer("/data/0/embedding"), response.pointer("/usage/prompt_tokens")),
+            Api::Ollama => (response.pointer("/embeddings/0"), response.get("prompt_eval_count")),
+            api => bail!("{:?} has no embeddings API, use openai, azure or ollama for memory.embedding", api),
+        };
+        self.usage.record(backend, &Usage { prompt_tokens: prompt_tokens.and_then(Value::as_u64).unwrap_or(0), completion_tokens: 0 });
+        serde_json::from_value(embedding.context("No embedding")?.clone()).context("Invalid embedding")
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
@@ -0,0 +1,67 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, Context, bail};
+
+use crate::config::WhisperConfig;
+
+const WHISPER_MODELS_URL: &str = "https://huggingface.co/ggerganov/whisper.cpp/resolve/main";
+
+// The configured model file, or the named model from the cache dir, downloaded
+// first if it isn't there yet
+pub async fn whisper_model(config: &WhisperConfig) -> Result<PathBuf> {
+    if let Some(path) = &config.model_path {
+        return Ok(PathBuf::from(path));
+    }
+
+    let dir = match &config.cache_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::cache_dir().unwrap_or_else(|| PathBuf::from(".")).join("assistant"),
+    };
+    let file_name = format!("ggml-{}.bin", config.model);
+    let path = dir.join(&file_name);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let url = format!("{}/{}", WHISPER_MODELS_URL, file_name);
+    println!("Downloading whisper model {:?} to {:?}", config.model, path);
+    download(&url, &path).await
+        .with_context(|| format!("Failed to download {}, check whisper.model", url))?;
+    Ok(path)
+}
+
+async fn download(url: &str, path: &Path) -> Result<()> {
+    let mut response = reqwest::get(url).await?;
+    if !response.status().is_success() {
+        bail!("{}", response.status());
+    }
+    let total = response.content_length();
+
+    // Into a separate file first, so an interrupted download isn't mistaken
+    // for the model next time
+    let partial = path.with_extension("part");
+    let mut file = std::fs::File::create(&partial)?;
+    let mut downloaded: u64 = 0;
+    let mut last_percent = None;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk)?;
+        downloaded += chunk.len() as u64;
+
+        let percent = total.map(|total| downloaded * 100 / total.max(1));
+        if percent != last_percent {
+            last_percent = percent;
+            match total {
+                Some(total) => print!("\r{}% ({}/{} MB)", percent.unwrap(), downloaded / 1_000_000, total / 1_000_000),
+                None => print!("\r{} MB", downloaded / 1_000_000),
+            }
+            std::io::stdout().flush()?;
+        }
+    }
+    println!();
+
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, Context};
+use ringbuf::HeapProducer;
+
+use crate::audio::{InputResampler, SAMPLE_RATE, downmix_samples};
+use crate::config::{Downmix, NetworkFormat, NetworkInputConfig};
+
+// UDP audio input. Packets are raw little-endian interleaved samples in the
+// configured format. A sender can announce what it streams instead with a
+// packet of b"FMT " followed by the sample rate (u32), channel count (u16) and
+// format (u8, 0 = pcm16, 1 = float32), all little-endian
+pub struct Receiver {
+    running: Arc<AtomicBool>,
+    _thread: JoinHandle<()>,
+}
+impl Receiver {
+    pub fn start(config: &NetworkInputConfig, downmix: Downmix, producer: HeapProducer<f32>, failed: Arc<AtomicBool>) -> Result<Self> {
+        let socket = UdpSocket::bind(&config.bind)
+            .with_context(|| format!("Failed to listen for audio on {}", config.bind))?;
+        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+        println!("Listening for audio on {}", socket.local_addr()?);
+
+        let format = Format {
+            encoding: config.format,
+            sample_rate: config.sample_rate,
+            channels: config.channels,
+        };
+        let jitter = JitterBuffer::new((SAMPLE_RATE as usize * config.jitter_ms as usize) / 1000);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = std::thread::spawn(move || {
+            if let Err(err) = receive(socket, format, downmix, jitter, producer, thread_running) {
+                eprintln!("Network input failed: {:?}", err);
+                failed.store(true, Ordering::Relaxed);
+            }
+        });
+
+        Ok(Receiver { running, _thread: thread })
+    }
+}
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        // Frees up the socket for a restarted capture
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Format {
+    encoding: NetworkFormat,
+    sample_rate: u32,
+    channels: u16,
+}
+impl Format {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() != 11 || &packet[..4] != b"FMT " {
+            return None;
+        }
+        let encoding = match packet[10] {
+            0 => NetworkFormat::Pcm16,
+            1 => NetworkFormat::Float32,
+            _ => return None,
+        };
+        Some(Format {
+            encoding,
+            sample_rate: u32::from_le_bytes(packet[4..8].try_into().unwrap()),
+            channels: u16::from_le_bytes(packet[8..10].try_into().unwrap()),
+        })
+    }
+    fn decode(&self, packet: &[u8]) -> Vec<f32> {
+        match self.encoding {
+            NetworkFormat::Pcm16 => packet.chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32)
+                .collect(),
+            NetworkFormat::Float32 => packet.chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect(),
+        }
+    }
+}
+
+fn receive(socket: UdpSocket, mut format: Format, downmix: Downmix, mut jitter: JitterBuffer, mut producer: HeapProducer<f32>, running: Arc<AtomicBool>) -> Result<()> {
+    let mut resampler = InputResampler::new(format.sample_rate)?;
+    let mut packet = [0u8; 65536];
+    while running.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut packet) {
+            Ok((length, sender)) => {
+                let packet = &packet[..length];
+                if let Some(announced) = Format::parse(packet) {
+                    if announced != format {
+                        println!("{} is streaming {:?}", sender, announced);
+                        format = announced;
+                        resampler = InputResampler::new(format.sample_rate)?;
+                        jitter.reset();
+                    }
+                    continue;
+                }
+
+                let samples = format.decode(packet);
+                jitter.push(resampler.process(downmix_samples(&samples, format.channels as usize, downmix)));
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        producer.push_slice(&jitter.pop(Instant::now()));
+    }
+    Ok(())
+}
+
+// Holds back jitter_ms of audio, then plays it out at SAMPLE_RATE regardless
+// of how the packets arrived. Starts over after running dry
+struct JitterBuffer {
+    pending: VecDeque<f32>,
+    target: usize,
+    // When playout started and how many samples have gone out since
+    playout: Option<(Instant, usize)>,
+}
+impl JitterBuffer {
+    fn new(target: usize) -> Self {
+        JitterBuffer { pending: VecDeque::new(), target: target.max(1), playout: None }
+    }
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.playout = None;
+    }
+    fn push(&mut self, samples: Vec<f32>) {
+        self.pending.extend(samples);
+        // The sender's clock runs fast or a burst came in, catch up
+        if self.pending.len() > self.target * 4 {
+            let excess = self.pending.len() - self.target;
+            self.pending.drain(..excess);
+        }
+    }
+    fn pop(&mut self, now: Instant) -> Vec<f32> {
+        let Some((start, released)) = self.playout else {
+            if self.pending.len() >= self.target {
+                self.playout = Some((now, 0));
+            }
+            return Vec::new();
+        };
+
+        let due = ((now - start).as_secs_f64() * SAMPLE_RATE as f64) as usize - released;
+        if due > self.pending.len() + self.target {
+            // Starved for longer than the buffer covers, prime it again
+            self.playout = None;
+            return self.pending.drain(..).collect();
+        }
+        let count = due.min(self.pending.len());
+        self.playout = Some((start, released + due));
+        self.pending.drain(..count).collect()
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use chrono::Local;
+use regex::Regex;
+
+use crate::config::OfflineConfig;
+use crate::tools::Tools;
+use crate::transcript::{Transcript, numbers_to_digits};
+
+static DURATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d+(?:\.\d+)?|an?)\s*(second|sec|minute|min|hour)s?\b").unwrap());
+static CONVERSION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(-?\d+(?:\.\d+)?)\s*(?:degrees?\s+)?([a-z]+)\s+(?:in|to|into)\s+(?:degrees?\s+)?([a-z]+)").unwrap()
+});
+
+// Spoken names for a unit, what it measures and how many metres, grams or
+// litres one is
+const UNITS: &[(&[&str], &str, f64)] = &[
+    (&["millimeter", "millimeters", "millimetre", "millimetres", "mm"], "length", 0.001),
+    (&["centimeter", "centimeters", "centimetre", "centimetres", "cm"], "length", 0.01),
+    (&["meter", "meters", "metre", "metres"], "length", 1.),
+    (&["kilometer", "kilometers", "kilometre", "kilometres", "km"], "length", 1000.),
+    (&["inch", "inches"], "length", 0.0254),
+    (&["foot", "feet"], "length", 0.3048),
+    (&["yard", "yards"], "length", 0.9144),
+    (&["mile", "miles"], "length", 1609.344),
+    (&["milligram", "milligrams", "mg"], "mass", 0.001),
+    (&["gram", "grams", "g"], "mass", 1.),
+    (&["kilogram", "kilograms", "kilo", "kilos", "kg"], "mass", 1000.),
+    (&["ounce", "ounces", "oz"], "mass", 28.349523125),
+    (&["pound", "pounds", "lb", "lbs"], "mass", 453.59237),
+    (&["stone", "stones"], "mass", 6350.29318),
+    (&["milliliter", "milliliters", "millilitre", "millilitres", "ml"], "volume", 0.001),
+    (&["liter", "liters", "litre", "litres"], "volume", 1.),
+    (&["teaspoon", "teaspoons"], "volume", 0.00492892),
+    (&["tablespoon", "tablespoons"], "volume", 0.0147868),
+    (&["cup", "cups"], "volume", 0.236588),
+    (&["pint", "pints"], "volume", 0.473176),
+    (&["quart", "quarts"], "volume", 0.946353),
+    (&["gallon", "gallons"], "volume", 3.78541),
+];
+
+// What can still be answered without the LLM: timers, the time and date, unit
+// conversions and offline.replies. None when it's none of those
+pub fn answer(transcript: &Transcript, config: &OfflineConfig, tools: &Tools) -> Option<String> {
+    if !config.enabled {
+        return None;
+    }
+    let text = numbers_to_digits(transcript.normalized());
+    let has = |word: &str| text.split(|c: char| !c.is_alphanumeric()).any(|token| token == word);
+
+    if has("timer") && tools.timers_enabled() {
+        if has("cancel") || has("stop") {
+            return Some(match tools.cancel_timers(None) {
+                0 => "There aren't any timers running.".to_string(),
+                _ => "Timer cancelled.".to_string(),
+            });
+        }
+        if let Some(captures) = DURATION.captures(&text) {
+            let amount: f64 = captures[1].parse().unwrap_or(1.);
+            let (unit, seconds) = match &captures[2] {
+                "second" | "sec" => ("second", 1.),
+                "minute" | "min" => ("minute", 60.),
+                _ => ("hour", 3600.),
+            };
+            let label = format!("{} {}", format_number(amount), unit);
+            let set = Duration::try_from_secs_f64(amount * seconds).ok()
+                .and_then(|duration| tools.set_timer(&label, duration).ok());
+            return Some(match set {
+                Some(()) => format!("Okay, {} timer starting now.", label),
+                None => "That's not a valid length for a timer.".to_string(),
+            });
+        }
+    }
+    if (has("time") && !has("timer")) || has("clock") {
+        return Some(format!("It's {}.", Local::now().format("%-I:%M %p")));
+    }
+    if has("date") || text.contains("what day") {
+        return Some(format!("It's {}.", Local::now().format("%A, %B %-d")));
+    }
+    if let Some(response) = convert(&text) {
+        return Some(response);
+    }
+    config.replies.iter()
+        .find(|reply| reply.phrases.iter().any(|phrase| text.contains(&phrase.to_lowercase())))
+        .map(|reply| reply.response.clone())
+}
+
+// "5 miles in kilometers", "100 degrees fahrenheit to celsius"
+fn convert(text: &str) -> Option<String> {
+    let captures = CONVERSION.captures(text)?;
+    let amount: f64 = captures[1].parse().ok()?;
+    let (from, to) = (&captures[2], &captures[3]);
+
+    let converted = match (to_kelvin(amount, from), to_kelvin(0., to)) {
+        (Some(kelvin), Some(_)) => from_kelvin(kelvin, to)?,
+        _ => {
+            let unit = |name: &str| UNITS.iter().find(|(names, _, _)| names.contains(&name)).map(|&(_, kind, factor)| (kind, factor));
+            let ((from_kind, from_factor), (to_kind, to_factor)) = (unit(from)?, unit(to)?);
+            if from_kind != to_kind {
+                return None;
+            }
+            amount * from_factor / to_factor
+        },
+    };
+    Some(format!("{} {} is {} {}.", format_number(amount), from, format_number(converted), to))
+}
+
+fn to_kelvin(amount: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "celsius" | "centigrade" => Some(amount + 273.15),
+        "fahrenheit" => Some((amount - 32.) * 5. / 9. + 273.15),
+        "kelvin" => Some(amount),
+        _ => None,
+    }
+}
+fn from_kelvin(kelvin: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "celsius" | "centigrade" => Some(kelvin - 273.15),
+        "fahrenheit" => Some((kelvin - 273.15) * 9. / 5. + 32.),
+        "kelvin" => Some(kelvin),
+        _ => None,
+    }
+}
+
+// At most two decimals, and none for whole numbers
+fn format_number(value: f64) -> String {
+    let formatted = format!("{:.2}", value);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
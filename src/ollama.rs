@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use anyhow::{Result, Context, bail};
+use serde_json::{Value, json};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::chat::{Backend, Entry, FunctionCall, ResponseFormat, ToolCall};
+use crate::llm::{BoxFuture, Completion, LanguageModel, RequestOptions, Usage, check_status, read_lines};
+
+// Ollama's native API, which unlike its OpenAI one can keep the model loaded
+// and tell whether it is
+pub struct Ollama {
+    backend: Backend,
+    client: reqwest::Client,
+}
+impl Ollama {
+    pub fn new(backend: &Backend, client: &reqwest::Client) -> Self {
+        Ollama { backend: backend.clone(), client: client.clone() }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.backend.url.trim_end_matches('/'), path)
+    }
+
+    fn chat_request(&self, messages: &[Entry], options: &RequestOptions, stream: bool) -> Value {
+        let backend = &self.backend;
+        let mut model_options = json!({});
+        if let Some(temperature) = backend.temperature {
+            model_options["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = backend.top_p {
+            model_options["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = backend.max_tokens {
+            model_options["num_predict"] = json!(max_tokens);
+        }
+        let mut request = json!({
+            "model": backend.model,
+            "messages": ollama_messages(messages),
+            "stream": stream,
+            "options": model_options,
+        });
+        // Same schemas as OpenAI's
+        if !options.tools.is_empty() {
+            request["tools"] = json!(options.tools);
+        }
+        if let Some(keep_alive) = &backend.keep_alive {
+            request["keep_alive"] = json!(keep_alive);
+        }
+        match (backend.response_format, &options.schema) {
+            (ResponseFormat::Json, Some(_)) => request["format"] = json!("json"),
+            (ResponseFormat::Schema, Some(schema)) => request["format"] = schema.clone(),
+            _ => {},
+        }
+        request
+    }
+
+    async fn complete(&self, messages: &[Entry], options: &RequestOptions, deltas: Option<UnboundedSender<String>>) -> Result<(Entry, Usage)> {
+        let res = self.client.post(self.url("/api/chat"))
+            .json(&self.chat_request(messages, options, deltas.is_some()))
+            .send()
+            .await?;
+        let res = check_status(res).await?;
+        let tokens_used = |response: &Value| Usage {
+            prompt_tokens: response["prompt_eval_count"].as_u64().unwrap_or(0),
+            completion_tokens: response["eval_count"].as_u64().unwrap_or(0),
+        };
+
+        let Some(deltas) = deltas else {
+            let response: Value = res.json().await?;
+            let calls = tool_calls(&response["message"]);
+            if !calls.is_empty() {
+                return Ok((Entry::ToolCalls(calls), tokens_used(&response)));
+            }
+            let content = response.pointer("/message/content").and_then(Value::as_str).context("No message")?;
+            return Ok((Entry::Assistant(content.to_string()), tokens_used(&response)));
+        };
+
+        // A JSON object per line, the last one with done and the counts
+        let mut content = String::new();
+        // Tool calls come whole, in one of the lines
+        let mut calls: Vec<ToolCall> = Vec::new();
+        let mut tokens = Usage::default();
+        read_lines(res, |line| {
+            if line.is_empty() {
+                return Ok(true);
+            }
+            let response: Value = serde_json::from_str(line).context("Invalid response line")?;
+            if let Some(delta) = response.pointer("/message/content").and_then(Value::as_str) {
+                content.push_str(delta);
+                let _ = deltas.send(delta.to_string());
+            }
+            calls.extend(tool_calls(&response["message"]));
+            if response["done"].as_bool() == Some(true) {
+                tokens = tokens_used(&response);
+                return Ok(false);
+            }
+            Ok(true)
+        }).await?;
+
+        if !calls.is_empty() {
+            for (i, call) in calls.iter_mut().enumerate() {
+                call.id = format!("call_{}", i);
+            }
+            return Ok((Entry::ToolCalls(calls), tokens));
+        }
+        Ok((Entry::Assistant(content), tokens))
+    }
+
+    // Pulls the model if the server doesn't have it, and starts loading it if
+    // it isn't loaded, so the first command doesn't wait on either
+    async fn pull_and_load(&self) -> Result<()> {
+        let model = &self.backend.model;
+        let names = |response: &Value| -> Vec<String> {
+            response["models"].as_array()
+                .map(|models| models.iter().filter_map(|model| model["name"].as_str().map(str::to_string)).collect())
+                .unwrap_or_default()
+        };
+        // "llama3.2" is "llama3.2:latest" to Ollama
+        let matches = |name: &String| name == model || name.strip_suffix(":latest") == Some(model);
+
+        let tags: Value = self.client.get(self.url("/api/tags")).send().await?.error_for_status()?.json().await?;
+        if !names(&tags).iter().any(matches) {
+            println!("Pulling {:?} with Ollama, this can take a while...", model);
+            let res = self.client.post(self.url("/api/pull"))
+                .json(&json!({ "model": model }))
+                .send()
+                .await?
+                .error_for_status()?;
+            let mut last_status = String::new();
+            read_lines(res, |line| {
+                if line.is_empty() {
+                    return Ok(true);
+                }
+                let progress: Value = serde_json::from_str(line)?;
+                if let Some(error) = progress["error"].as_str() {
+                    bail!("Failed to pull {:?}: {}", model, error);
+                }
+                let status = progress["status"].as_str().unwrap_or_default();
+                if status != last_status {
+                    println!("{}", status);
+                    last_status = status.to_string();
+                }
+                Ok(true)
+            }).await?;
+        }
+
+        let running: Value = self.client.get(self.url("/api/ps")).send().await?.error_for_status()?.json().await?;
+        if !names(&running).iter().any(matches) {
+            println!("{:?} isn't loaded yet, loading it now (the first answer may be slow until it is)", model);
+            // An empty chat just loads the model
+            let mut request = json!({ "model": model, "messages": [] });
+            if let Some(keep_alive) = &self.backend.keep_alive {
+                request["keep_alive"] = json!(keep_alive);
+            }
+            let load = self.client.post(self.url("/api/chat")).json(&request).timeout(Duration::from_secs(600)).send();
+            tokio::spawn(async move {
+                match load.await.and_then(|res| res.error_for_status()) {
+                    Ok(_) => println!("Ollama model loaded"),
+                    Err(err) => eprintln!("Failed to load the Ollama model: {:?}", err),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+impl LanguageModel for Ollama {
+    fn request<'a>(&'a self, messages: &'a [Entry], options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a> {
+        Box::pin(self.complete(messages, options, deltas))
+    }
+    fn prepare(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.pull_and_load())
+    }
+}
+
+// Ollama has tool call arguments as an object rather than a string, and
+// matches results up by the tool's name rather than an id
+fn ollama_messages(messages: &[Entry]) -> Vec<Value> {
+    messages.iter()
+        .map(|entry| match entry {
+            Entry::ToolCalls(calls) => json!({
+                "role": "assistant",
+                "content": "",
+                "tool_calls": calls.iter().map(|call| json!({
+                    "function": {
+                        "name": call.function.name,
+                        "arguments": serde_json::from_str::<Value>(&call.function.arguments).unwrap_or_else(|_| json!({})),
+                    },
+                })).collect::<Vec<_>>(),
+            }),
+            Entry::Tool { name, content, .. } => json!({ "role": "tool", "content": content, "tool_name": name }),
+            // Plain base64 rather than a data: URL
+            Entry::UserImage { content, image } => json!({
+                "role": "user",
+                "content": content,
+                "images": [image.split_once(";base64,").map_or(image.as_str(), |(_, data)| data)],
+            }),
+            entry => json!(entry),
+        })
+        .collect()
+}
+
+// Calls come without ids, so they're numbered
+fn tool_calls(message: &Value) -> Vec<ToolCall> {
+    message["tool_calls"].as_array().into_iter().flatten().enumerate()
+        .map(|(i, call)| ToolCall {
+            id: format!("call_{}", i),
+            function: FunctionCall {
+                name: call.pointer("/function/name").and_then(Value::as_str).unwrap_or_default().to_string(),
+                arguments: call.pointer("/function/arguments").map_or_else(|| "{}".to_string(), Value::to_string),
+            },
+            ..Default::default()
+        })
+        .collect()
+}
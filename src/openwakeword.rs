@@ -0,0 +1,138 @@
+use anyhow::{Result, anyhow, bail};
+use ort::session::Session;
+use ort::value::Tensor;
+
+use crate::config::WakewordConfig;
+use crate::wakeword::{Detection, WakeDetector, WakewordSource};
+
+// openWakeWord runs three models: audio -> mel spectrogram frames, 76 mel
+// frames -> a speech embedding, and the last 16 embeddings -> a wakeword score
+// per wakeword model. Audio goes in 80ms at a time
+const CHUNK_LENGTH: usize = 1280;
+// The mel spectrogram needs a few frames of the previous chunk
+const MEL_CONTEXT: usize = 160 * 3;
+const MEL_BINS: usize = 32;
+const EMBEDDING_WINDOW: usize = 76;
+const EMBEDDING_LENGTH: usize = 96;
+const MODEL_WINDOW: usize = 16;
+
+pub struct OpenWakeWordDetector {
+    melspectrogram: Session,
+    embedding: Session,
+    models: Vec<(String, Session)>,
+    raw: Vec<f32>,
+    mels: Vec<[f32; MEL_BINS]>,
+    embeddings: Vec<Vec<f32>>,
+    threshold: f32,
+    partial: Option<Detection>,
+}
+impl OpenWakeWordDetector {
+    pub fn new(wakewords: Vec<(String, WakewordSource)>, config: &WakewordConfig) -> Result<Self> {
+        let models = wakewords.into_iter()
+            .map(|(name, source)| match source {
+                WakewordSource::Model(path) => Ok((name, load(&path)?)),
+                WakewordSource::Clips(_) => bail!("openWakeWord needs a model for {:?}, set its model to an .onnx file", name),
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(OpenWakeWordDetector {
+            melspectrogram: load(&config.openwakeword_melspectrogram_path)?,
+            embedding: load(&config.openwakeword_embedding_path)?,
+            models,
+            raw: Vec::new(),
+            mels: Vec::new(),
+            embeddings: Vec::new(),
+            threshold: config.threshold,
+            partial: None,
+        })
+    }
+
+    fn push_mels(&mut self) -> Result<()> {
+        let input = self.raw.clone();
+        let outputs = self.melspectrogram.run(ort::inputs![Tensor::from_array(([1, input.len()], input))?])?;
+        let (_, mels) = outputs[0].try_extract_tensor::<f32>()?;
+        for frame in mels.chunks_exact(MEL_BINS) {
+            // Same scaling openWakeWord's embedding model was trained on
+            self.mels.push(std::array::from_fn(|i| frame[i] / 10. + 2.));
+        }
+        drop(outputs);
+
+        let excess = self.mels.len().saturating_sub(EMBEDDING_WINDOW);
+        self.mels.drain(..excess);
+        Ok(())
+    }
+    fn push_embedding(&mut self) -> Result<()> {
+        let window: Vec<f32> = self.mels.iter().flatten().copied().collect();
+        let outputs = self.embedding.run(ort::inputs![Tensor::from_array(([1, EMBEDDING_WINDOW, MEL_BINS, 1], window))?])?;
+        let (_, embedding) = outputs[0].try_extract_tensor::<f32>()?;
+        self.embeddings.push(embedding.to_vec());
+        drop(outputs);
+
+        let excess = self.embeddings.len().saturating_sub(MODEL_WINDOW);
+        self.embeddings.drain(..excess);
+        Ok(())
+    }
+    fn score(&mut self) -> Result<Option<Detection>> {
+        let features: Vec<f32> = self.embeddings.iter().flatten().copied().collect();
+        let mut best: Option<Detection> = None;
+        for (name, model) in &mut self.models {
+            let outputs = model.run(ort::inputs![Tensor::from_array(([1, MODEL_WINDOW, EMBEDDING_LENGTH], features.clone()))?])?;
+            let score = outputs[0].try_extract_tensor::<f32>()?.1[0];
+            if best.as_ref().map_or(true, |best| score > best.score) {
+                best = Some(Detection { name: name.clone(), score, avg_score: score, counter: 1 });
+            }
+        }
+        Ok(best)
+    }
+    fn process_chunk(&mut self, chunk: &[f32]) -> Result<Option<Detection>> {
+        // The models expect int16 sample values
+        self.raw.extend(chunk.iter().map(|sample| sample * i16::MAX as f32));
+        let excess = self.raw.len().saturating_sub(CHUNK_LENGTH + MEL_CONTEXT);
+        self.raw.drain(..excess);
+
+        self.push_mels()?;
+        if self.mels.len() < EMBEDDING_WINDOW {
+            return Ok(None);
+        }
+        self.push_embedding()?;
+        if self.embeddings.len() < MODEL_WINDOW {
+            return Ok(None);
+        }
+
+        match self.score()? {
+            Some(detection) if detection.score >= self.threshold => {
+                // Otherwise the same utterance keeps scoring high for a while
+                self.embeddings.clear();
+                self.partial = None;
+                Ok(Some(detection))
+            },
+            partial => {
+                self.partial = partial;
+                Ok(None)
+            },
+        }
+    }
+}
+impl WakeDetector for OpenWakeWordDetector {
+    fn samples_per_frame(&self) -> usize {
+        CHUNK_LENGTH
+    }
+    fn process(&mut self, frame: &[f32]) -> Option<Detection> {
+        match self.process_chunk(frame) {
+            Ok(detection) => detection,
+            Err(err) => {
+                eprintln!("openWakeWord failed: {:?}", err);
+                None
+            }
+        }
+    }
+    fn partial(&self) -> Option<Detection> {
+        self.partial.clone()
+    }
+}
+
+fn load(path: &str) -> Result<Session> {
+    Session::builder()?
+        .commit_from_file(path)
+        .map_err(|err| anyhow!("Failed to load {:?}: {}", path, err))
+}
@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use cpal::Device;
+use ringbuf::{HeapRb, HeapProducer, HeapConsumer};
+use rodio::{OutputStream, Sink, Decoder, Source};
+use rodio::source::UniformSourceIterator;
+
+use crate::audio::SAMPLE_RATE;
+use crate::config::Ducking;
+
+// Where responses and cues get played, the local device or a satellite
+pub trait AudioOutput {
+    fn play_file(&self, path: &str);
+    fn play_wav(&self, wav: Vec<u8>);
+    // Drop everything queued, appending afterwards plays again
+    fn stop(&self);
+    // Get out of the way while the user is talking
+    fn duck(&self, ducking: Ducking);
+    fn restore(&self);
+    // Volume everything plays at, ducking is relative to it
+    fn set_volume(&self, volume: f32);
+    fn is_playing(&self) -> bool;
+}
+
+pub async fn wait_until_done(output: &impl AudioOutput) {
+    while output.is_playing() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// Everything played goes through here so it can be used as the echo reference
+pub struct Output {
+    // https://github.com/RustAudio/rodio/issues/330
+    _stream: OutputStream,
+    sink: Sink,
+    volume: Mutex<f32>,
+    reference: Arc<Mutex<HeapProducer<f32>>>,
+}
+impl Output {
+    // Also returns the consumer for the played audio, mono at SAMPLE_RATE
+    pub fn new(device: &Device) -> Result<(Self, HeapConsumer<f32>)> {
+        let (stream, stream_handle) = OutputStream::try_from_device(device)?;
+        let sink = Sink::try_new(&stream_handle)?;
+        let (producer, consumer) = HeapRb::<f32>::new(SAMPLE_RATE as usize * 2).split();
+
+        Ok((Output {
+            _stream: stream,
+            sink,
+            volume: Mutex::new(1.),
+            reference: Arc::new(Mutex::new(producer)),
+        }, consumer))
+    }
+
+    fn append<R: Read + Seek + Send + Sync + 'static>(&self, decoder: Decoder<R>) {
+        let source = UniformSourceIterator::<_, f32>::new(decoder, 1, SAMPLE_RATE);
+        self.sink.append(Tap { source, reference: self.reference.clone() });
+    }
+}
+impl AudioOutput for Output {
+    fn play_file(&self, path: &str) {
+        let open = || -> Result<_> { Ok(Decoder::new(File::open(path)?)?) };
+        match open() {
+            Ok(decoder) => self.append(decoder),
+            Err(err) => eprintln!("Failed to play {:?}: {:?}", path, err),
+        }
+    }
+    fn play_wav(&self, wav: Vec<u8>) {
+        self.append(Decoder::new(Cursor::new(wav)).expect("Failed to decode audio"));
+    }
+    fn stop(&self) {
+        self.sink.stop();
+    }
+    fn duck(&self, ducking: Ducking) {
+        match ducking {
+            Ducking::None => {},
+            Ducking::Volume(volume) => self.sink.set_volume(volume * *self.volume.lock().unwrap()),
+            Ducking::Pause => self.sink.pause(),
+        }
+    }
+    fn restore(&self) {
+        self.sink.set_volume(*self.volume.lock().unwrap());
+        self.sink.play();
+    }
+    fn set_volume(&self, volume: f32) {
+        *self.volume.lock().unwrap() = volume;
+        self.sink.set_volume(volume);
+    }
+    fn is_playing(&self) -> bool {
+        !self.sink.empty()
+    }
+}
+
+// Passes samples through while copying them into the reference buffer
+struct Tap<S> {
+    source: S,
+    reference: Arc<Mutex<HeapProducer<f32>>>,
+}
+impl<S: Source<Item = f32>> Iterator for Tap<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.source.next()?;
+        let _ = self.reference.lock().unwrap().push(sample);
+        Some(sample)
+    }
+}
+impl<S: Source<Item = f32>> Source for Tap<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+// Runs code in an interpreter inside this process, where variables stick
+// around from one run to the next ("store that in x", then "now print x
+// squared"). Returns what it printed, errors included. Code still running
+// after the timeout has a KeyboardInterrupt raised in it
+#[cfg(feature = "python")]
+pub async fn run(code: &str, timeout: Duration) -> Result<String> {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use pyo3::prelude::*;
+
+    // Python's id for the thread running it once it starts, -1 when it
+    // shouldn't anymore
+    let thread = Arc::new(AtomicI64::new(0));
+    let running = {
+        let (code, thread) = (code.to_string(), thread.clone());
+        tokio::task::spawn_blocking(move || run_blocking(&code, &thread))
+    };
+    match tokio::time::timeout(timeout, running).await {
+        Ok(result) => result?,
+        Err(_) => {
+            let id = thread.swap(-1, Ordering::SeqCst);
+            // Stops at its next bytecode. Not waited for, a call into C (like
+            // time.sleep) only sees it once it returns
+            if id > 0 {
+                tokio::task::spawn_blocking(move || Python::with_gil(|_| unsafe {
+                    pyo3::ffi::PyThreadState_SetAsyncExc(id as _, pyo3::ffi::PyExc_KeyboardInterrupt);
+                })).await?;
+            }
+            anyhow::bail!("Stopped, it ran for longer than {:?}", timeout)
+        },
+    }
+}
+#[cfg(feature = "python")]
+fn run_blocking(code: &str, thread: &std::sync::atomic::AtomicI64) -> Result<String> {
+    use std::sync::OnceLock;
+    use std::sync::atomic::Ordering;
+
+    use pyo3::prelude::*;
+    use pyo3::types::PyDict;
+
+    static GLOBALS: OnceLock<Py<PyDict>> = OnceLock::new();
+    Python::with_gil(|py| {
+        let id: u64 = py.import_bound("threading")?.call_method0("get_ident")?.extract()?;
+        if thread.compare_exchange(0, id as i64, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            anyhow::bail!("Timed out waiting for the interpreter");
+        }
+        let globals = GLOBALS.get_or_init(|| PyDict::new_bound(py).unbind()).bind(py);
+        let sys = py.import_bound("sys")?;
+        let printed = py.import_bound("io")?.call_method0("StringIO")?;
+        let (stdout, stderr) = (sys.getattr("stdout")?, sys.getattr("stderr")?);
+        sys.setattr("stdout", &printed)?;
+        sys.setattr("stderr", &printed)?;
+        let result = py.run_bound(code, Some(globals), None);
+        sys.setattr("stdout", stdout)?;
+        sys.setattr("stderr", stderr)?;
+
+        let mut printed: String = printed.call_method0("getvalue")?.extract()?;
+        if let Err(err) = result {
+            printed.push_str(&err.to_string());
+        }
+        Ok(printed.trim_end().to_string())
+    })
+}
+#[cfg(not(feature = "python"))]
+pub async fn run(_code: &str, _timeout: Duration) -> Result<String> {
+    anyhow::bail!("Built without embedded Python support, rebuild with --features python")
+}
@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use chrono::Utc;
+use hound::{WavSpec, WavWriter};
+use serde::Serialize;
+
+use crate::audio::resample_linear;
+use crate::config::{RecordingConfig, RecordingFormat};
+use crate::store::Store;
+use crate::transcript::Transcript;
+
+// Write a mono utterance in the configured format, resampling if needed
+pub fn write_wav(path: impl AsRef<Path>, samples: &[f32], sample_rate: u32, config: &RecordingConfig) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let samples = resample_linear(samples, sample_rate, config.sample_rate);
+
+    match config.format {
+        RecordingFormat::Pcm16 => {
+            let mut writer = WavWriter::create(path, WavSpec {
+                channels: 1,
+                sample_rate: config.sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            })?;
+            for sample in samples {
+                writer.write_sample((sample.clamp(-1., 1.) * i16::MAX as f32) as i16)?;
+            }
+            writer.finalize()?;
+        },
+        RecordingFormat::Float32 => {
+            let mut writer = WavWriter::create(path, WavSpec {
+                channels: 1,
+                sample_rate: config.sample_rate,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            })?;
+            for sample in samples {
+                writer.write_sample(sample)?;
+            }
+            writer.finalize()?;
+        },
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct TranscriptRecord<'a> {
+    audio: String,
+    duration_ms: u128,
+    segments: &'a [String],
+    transcript: &'a str,
+}
+
+// Written next to the WAV with the same name, so misrecognitions can be checked
+// against the audio
+pub fn write_transcript(wav_path: impl AsRef<Path>, duration: Duration, segments: &[String], transcript: &str) -> Result<()> {
+    let wav_path = wav_path.as_ref();
+    let record = TranscriptRecord {
+        audio: wav_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        duration_ms: duration.as_millis(),
+        segments,
+        transcript,
+    };
+    std::fs::write(wav_path.with_extension("json"), serde_json::to_string_pretty(&record)?)?;
+    Ok(())
+}
+
+struct StoredRecording {
+    files: Vec<PathBuf>,
+    modified: SystemTime,
+    size: u64,
+}
+
+// Recordings grouped by name, so a WAV and its transcript are removed together
+fn stored_recordings(dir: &Path) -> Result<Vec<StoredRecording>> {
+    let mut recordings: Vec<(String, StoredRecording)> = Vec::new();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_recording = matches!(path.extension().and_then(|e| e.to_str()), Some("wav") | Some("json"));
+        if !is_recording {
+            continue;
+        }
+        let metadata = std::fs::metadata(&path)?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let modified = metadata.modified()?;
+
+        match recordings.iter_mut().find(|(name, _)| *name == stem) {
+            Some((_, recording)) => {
+                recording.files.push(path);
+                recording.modified = recording.modified.max(modified);
+                recording.size += metadata.len();
+            },
+            None => recordings.push((stem, StoredRecording { files: vec![path], modified, size: metadata.len() })),
+        }
+    }
+
+    let mut recordings: Vec<_> = recordings.into_iter().map(|(_, recording)| recording).collect();
+    recordings.sort_by_key(|recording| std::cmp::Reverse(recording.modified)); // Newest first
+    Ok(recordings)
+}
+
+fn remove(recording: &StoredRecording) -> Result<()> {
+    for file in &recording.files {
+        std::fs::remove_file(file)?;
+    }
+    Ok(())
+}
+
+// Drop recordings older than max_age_days, then the oldest ones until the
+// directory fits in max_size_mb. Returns how many were removed
+pub fn apply_retention(config: &RecordingConfig, store: Option<&Store>) -> Result<usize> {
+    let max_age = config.max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    // Transcripts logged to the store are kept as long as the recordings
+    if let (Some(store), Some(max_age)) = (store, max_age) {
+        store.remove_transcripts(Some(Utc::now() - chrono::Duration::from_std(max_age)?))?;
+    }
+    let Some(dir) = &config.dir else { return Ok(0) };
+    let max_size = config.max_size_mb.map(|mb| mb * 1024 * 1024);
+
+    let mut removed = 0;
+    let mut total_size = 0;
+    for recording in stored_recordings(Path::new(dir))? {
+        let age = SystemTime::now().duration_since(recording.modified).unwrap_or_default();
+        let too_old = max_age.is_some_and(|max_age| age > max_age);
+        let too_big = max_size.is_some_and(|max_size| total_size + recording.size > max_size);
+        if too_old || too_big {
+            remove(&recording)?;
+            removed += 1;
+        } else {
+            total_size += recording.size;
+        }
+    }
+    Ok(removed)
+}
+
+pub fn purge(config: &RecordingConfig, store: Option<&Store>) -> Result<usize> {
+    if let Some(store) = store {
+        store.remove_transcripts(None)?;
+    }
+    let Some(dir) = &config.dir else { return Ok(0) };
+    let recordings = stored_recordings(Path::new(dir))?;
+    for recording in &recordings {
+        remove(recording)?;
+    }
+    Ok(recordings.len())
+}
+
+pub fn is_purge_request(transcript: &Transcript, config: &RecordingConfig) -> bool {
+    config.purge_phrases.iter().any(|phrase| transcript.normalized().contains(&phrase.to_lowercase()))
+}
@@ -0,0 +1,247 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, Context, bail};
+use ringbuf::{HeapRb, HeapConsumer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender, error::TryRecvError};
+use tokio::time::timeout;
+
+use crate::audio::{self, Capture, SAMPLE_RATE};
+use crate::config::{Config, Ducking};
+use crate::output::{Output, AudioOutput};
+
+// How long the other end gets to say hello or take a message before the
+// connection is given up on
+const TIMEOUT: Duration = Duration::from_secs(5);
+// Payload limits: a spoken answer, a stretch of audio, and everything else
+const MAX_PLAY_LENGTH: usize = 16 * 1024 * 1024;
+const MAX_AUDIO_LENGTH: usize = 1024 * 1024;
+const MAX_LENGTH: usize = 64 * 1024;
+
+// Satellites and the server talk over TCP in frames of a kind byte, a u32
+// little-endian payload length and the payload
+#[derive(Debug)]
+enum Message {
+    // Satellite -> server, first message with the satellite's name
+    Hello(String),
+    // Satellite -> server, mono SAMPLE_RATE audio as i16
+    Audio(Vec<i16>),
+    // Satellite -> server, sent whenever playback starts or stops
+    Playing(bool),
+    // Server -> satellite, a WAV file to queue
+    Play(Vec<u8>),
+    Stop,
+    Duck(Ducking),
+    Restore,
+    Volume(f32),
+}
+impl Message {
+    async fn write(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let (kind, payload): (u8, Vec<u8>) = match self {
+            Message::Hello(name) => (0, name.as_bytes().to_vec()),
+            Message::Audio(samples) => (1, samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()),
+            Message::Playing(playing) => (2, vec![*playing as u8]),
+            Message::Play(wav) => (3, wav.clone()),
+            Message::Stop => (4, Vec::new()),
+            Message::Duck(ducking) => (5, serde_json::to_vec(ducking)?),
+            Message::Restore => (6, Vec::new()),
+            Message::Volume(volume) => (7, volume.to_le_bytes().to_vec()),
+        };
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        timeout(TIMEOUT, writer.write_all(&frame)).await.context("Timed out sending a message")??;
+        Ok(())
+    }
+    async fn read(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header).await?;
+        // Checked before anything's allocated for it, the length is up to
+        // whoever's on the other end
+        let length = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+        let max_length = match header[0] {
+            3 => MAX_PLAY_LENGTH,
+            1 => MAX_AUDIO_LENGTH,
+            _ => MAX_LENGTH,
+        };
+        if length > max_length {
+            bail!("A message of kind {} is {} bytes, more than the {} allowed", header[0], length, max_length);
+        }
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload).await?;
+
+        Ok(match header[0] {
+            0 => Message::Hello(String::from_utf8(payload)?),
+            1 => Message::Audio(payload.chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect()),
+            2 => Message::Playing(payload.first() == Some(&1)),
+            3 => Message::Play(payload),
+            4 => Message::Stop,
+            5 => Message::Duck(serde_json::from_slice(&payload)?),
+            6 => Message::Restore,
+            7 => Message::Volume(f32::from_le_bytes(payload[..].try_into()?)),
+            kind => bail!("Unknown message kind {}", kind),
+        })
+    }
+}
+
+// Server side of a connected satellite: its audio as a Capture, and an output
+// that plays on the satellite. Returns the satellite's name
+pub async fn accept(stream: TcpStream) -> Result<(String, Capture, RemoteOutput, HeapConsumer<f32>)> {
+    stream.set_nodelay(true)?;
+    let (mut reader, mut writer) = stream.into_split();
+    let hello = timeout(TIMEOUT, Message::read(&mut reader)).await.context("Timed out waiting for the satellite's hello")?;
+    let name = match hello? {
+        Message::Hello(name) => name,
+        message => bail!("Expected a hello from the satellite, got {:?}", message),
+    };
+
+    let (mut producer, consumer) = HeapRb::<f32>::new(SAMPLE_RATE as usize * 2).split();
+    let ended = Arc::new(AtomicBool::new(false));
+    let playing = Arc::new(AtomicBool::new(false));
+    let reader_ended = ended.clone();
+    let writer_ended = ended.clone();
+    let reader_playing = playing.clone();
+    tokio::spawn(async move {
+        loop {
+            match Message::read(&mut reader).await {
+                Ok(Message::Audio(samples)) => {
+                    producer.push_iter(&mut samples.into_iter().map(|sample| sample as f32 / i16::MAX as f32));
+                },
+                Ok(Message::Playing(playing)) => reader_playing.store(playing, Ordering::Relaxed),
+                Ok(message) => eprintln!("Unexpected message from satellite: {:?}", message),
+                Err(_) => break,
+            }
+        }
+        reader_ended.store(true, Ordering::Relaxed);
+    });
+
+    // Sent from a task of its own, so a stalled satellite doesn't hold up the
+    // session. A dead connection also ends the capture, so the session winds
+    // down
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Message>();
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if let Err(err) = message.write(&mut writer).await {
+                eprintln!("Failed to send to satellite: {:?}", err);
+                break;
+            }
+        }
+        writer_ended.store(true, Ordering::Relaxed);
+    });
+
+    // No echo reference, the audio plays out on the satellite
+    let (_, echo_reference) = HeapRb::<f32>::new(1).split();
+    let output = RemoteOutput { sender, playing };
+    Ok((name, Capture::external(consumer, ended), output, echo_reference))
+}
+
+pub struct RemoteOutput {
+    sender: UnboundedSender<Message>,
+    playing: Arc<AtomicBool>,
+}
+impl RemoteOutput {
+    fn send(&self, message: Message) {
+        // Fails once the connection is gone, which has already been reported
+        let _ = self.sender.send(message);
+    }
+}
+impl AudioOutput for RemoteOutput {
+    fn play_file(&self, path: &str) {
+        match std::fs::read(path) {
+            Ok(wav) => self.play_wav(wav),
+            Err(err) => eprintln!("Failed to read {:?}: {:?}", path, err),
+        }
+    }
+    fn play_wav(&self, wav: Vec<u8>) {
+        // Until the satellite reports back
+        self.playing.store(true, Ordering::Relaxed);
+        self.send(Message::Play(wav));
+    }
+    fn stop(&self) {
+        self.send(Message::Stop);
+    }
+    fn duck(&self, ducking: Ducking) {
+        self.send(Message::Duck(ducking));
+    }
+    fn restore(&self) {
+        self.send(Message::Restore);
+    }
+    fn set_volume(&self, volume: f32) {
+        self.send(Message::Volume(volume));
+    }
+    fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+}
+
+// Satellite mode: stream the microphone to the server and play whatever comes
+// back, reconnecting whenever the connection drops
+pub async fn run(server: &str, config: &Config) -> Result<()> {
+    let host = cpal::default_host();
+    let output_device = audio::output_device(&host, config.audio.output_device.as_deref())?;
+    let (output, _) = Output::new(&output_device)?;
+
+    loop {
+        match stream(server, config, &host, &output).await {
+            Ok(()) => println!("Disconnected from {}", server),
+            Err(err) => eprintln!("Connection to {} failed: {:?}", server, err),
+        }
+        output.stop();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+async fn stream(server: &str, config: &Config, host: &cpal::Host, output: &Output) -> Result<()> {
+    let stream = timeout(TIMEOUT, TcpStream::connect(server)).await.context("Timed out connecting")??;
+    stream.set_nodelay(true)?;
+    let (mut reader, mut writer) = stream.into_split();
+    Message::Hello(config.satellite.name.clone()).write(&mut writer).await?;
+    println!("Connected to {} as {:?}", server, config.satellite.name);
+
+    // Output isn't Send, so playback requests get handed back to this task
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(message) = Message::read(&mut reader).await {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut capture = Capture::start(host, &config.audio, config.debug.verbosity)?;
+    let mut playing = false;
+    loop {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if capture.failed() {
+            bail!("Audio input failed");
+        }
+
+        loop {
+            match receiver.try_recv() {
+                Ok(Message::Play(wav)) => output.play_wav(wav),
+                Ok(Message::Stop) => output.stop(),
+                Ok(Message::Duck(ducking)) => output.duck(ducking),
+                Ok(Message::Restore) => output.restore(),
+                Ok(Message::Volume(volume)) => output.set_volume(volume),
+                Ok(message) => eprintln!("Unexpected message from server: {:?}", message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        if output.is_playing() != playing {
+            playing = output.is_playing();
+            Message::Playing(playing).write(&mut writer).await?;
+        }
+
+        let samples: Vec<i16> = capture.consumer.pop_iter()
+            .map(|sample| (sample.clamp(-1., 1.) * i16::MAX as f32) as i16)
+            .collect();
+        if !samples.is_empty() {
+            Message::Audio(samples).write(&mut writer).await?;
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+static TYPE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""type"\s*:\s*"([^"]*)""#).expect("Invalid regex"));
+static RESPONSE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""response"\s*:\s*""#).expect("Invalid regex"));
+
+// Picks the "response" text out of a JSON answer while it's still streaming
+// in, and hands it out a sentence at a time so TTS can start early
+pub struct SentenceStream {
+    raw: String,
+    // Characters of the response already handed out
+    emitted: usize,
+}
+impl SentenceStream {
+    pub fn new() -> Self {
+        SentenceStream { raw: String::new(), emitted: 0 }
+    }
+    pub fn push(&mut self, delta: &str) -> Vec<String> {
+        self.raw.push_str(delta);
+        self.sentences(false)
+    }
+    // Whatever's left once the answer is complete
+    pub fn finish(&mut self) -> Vec<String> {
+        self.sentences(true)
+    }
+
+    fn sentences(&mut self, finished: bool) -> Vec<String> {
+        let Some((text, closed)) = response_text(&self.raw) else {
+            return Vec::new();
+        };
+        let rest: String = text.chars().skip(self.emitted).collect();
+
+        let mut sentences = Vec::new();
+        let mut start = 0;
+        let mut chars = rest.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            // Only once the next word starts, so "3.5" and "..." stay whole
+            let boundary = matches!(c, '.' | '!' | '?' | '\n') &&
+                chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+            if boundary {
+                let end = i + c.len_utf8();
+                sentences.push(rest[start..end].trim().to_string());
+                start = end;
+            }
+        }
+        if closed || finished {
+            sentences.push(rest[start..].trim().to_string());
+            start = rest.len();
+        }
+
+        self.emitted += rest[..start].chars().count();
+        sentences.retain(|sentence| !sentence.is_empty());
+        sentences
+    }
+}
+
+// The response string so far, and whether it's complete. None until it's
+// clear the answer is a plain "type": "response"
+fn response_text(raw: &str) -> Option<(String, bool)> {
+    let ty = TYPE.captures(raw)?;
+    let response = RESPONSE.find(raw)?;
+    if &ty[1] != "response" || ty.get(0)?.start() > response.start() {
+        return None;
+    }
+
+    let mut text = String::new();
+    let mut chars = raw[response.end()..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some((text, true)),
+            // An escape cut off by the end of a delta waits for the next one
+            '\\' => match chars.next() {
+                Some('n') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    if hex.len() < 4 {
+                        break;
+                    }
+                    text.push(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).unwrap_or(' '));
+                },
+                Some(escaped) => text.push(escaped),
+                None => break,
+            },
+            c => text.push(c),
+        }
+    }
+    Some((text, false))
+}
@@ -0,0 +1,173 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::audio::SAMPLE_RATE;
+use crate::config::{SpeakerConfig, SpeakerProfile};
+
+// 25ms frames every 10ms
+const FRAME_LEN: usize = 400;
+const HOP_LEN: usize = 160;
+const FFT_LEN: usize = 512;
+const MEL_BANDS: usize = 26;
+// Cepstral coefficients kept, after dropping the first (overall loudness)
+const COEFFICIENTS: usize = 12;
+// Frames this far (in dB) below the loudest one are treated as silence
+const DYNAMIC_RANGE_DB: f32 = 30.;
+
+// Tells enrolled speakers apart by the average shape of their voice (the mean
+// and spread of its MFCCs). Much cruder than a neural speaker model, but it
+// needs nothing extra and can tell a couple of people in a household apart
+pub struct SpeakerIdentifier {
+    profiles: Vec<(SpeakerProfile, Vec<f32>)>,
+    threshold: f32,
+    fft: Arc<dyn Fft<f32>>,
+    verbosity: u8,
+}
+impl SpeakerIdentifier {
+    pub fn new(config: &SpeakerConfig, verbosity: u8) -> Result<Self> {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_LEN);
+        let mut identifier = SpeakerIdentifier { profiles: Vec::new(), threshold: config.threshold, fft, verbosity };
+
+        for profile in &config.profiles {
+            let embeddings = profile.clips.iter()
+                .map(|clip| {
+                    let samples = read_clip(Path::new(clip))?;
+                    identifier.embed(&samples).with_context(|| format!("Not enough speech in {:?}", clip))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if embeddings.is_empty() {
+                bail!("Speaker {:?} has no clips", profile.name);
+            }
+            let mut average = vec![0.; embeddings[0].len()];
+            for embedding in &embeddings {
+                for (average, value) in average.iter_mut().zip(embedding) {
+                    *average += value / embeddings.len() as f32;
+                }
+            }
+            identifier.profiles.push((profile.clone(), average));
+        }
+
+        Ok(identifier)
+    }
+
+    // The enrolled speaker that sounds most like the command, if any is close
+    // enough
+    pub fn identify(&self, samples: &[f32]) -> Option<&SpeakerProfile> {
+        let embedding = self.embed(samples)?;
+        let (profile, similarity) = self.profiles.iter()
+            .map(|(profile, enrolled)| (profile, cosine_similarity(&embedding, enrolled)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        if self.verbosity >= 1 {
+            println!("Closest speaker {:?} ({:.3} similarity)", profile.name, similarity);
+        }
+        (similarity >= self.threshold).then_some(profile)
+    }
+
+    fn embed(&self, samples: &[f32]) -> Option<Vec<f32>> {
+        let window: Vec<f32> = (0..FRAME_LEN)
+            .map(|i| 0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / FRAME_LEN as f32).cos())
+            .collect();
+        let filters = mel_filters();
+
+        let mut frames = Vec::new();
+        let mut buffer = vec![Complex::new(0., 0.); FFT_LEN];
+        for frame in samples.windows(FRAME_LEN).step_by(HOP_LEN) {
+            buffer.fill(Complex::new(0., 0.));
+            for ((bin, sample), weight) in buffer.iter_mut().zip(frame).zip(&window) {
+                bin.re = sample * weight;
+            }
+            self.fft.process(&mut buffer);
+            let power: Vec<f32> = buffer[..FFT_LEN / 2 + 1].iter().map(|bin| bin.norm_sqr()).collect();
+
+            let log_mel: Vec<f32> = filters.iter()
+                .map(|filter| filter.iter().zip(&power).map(|(weight, power)| weight * power).sum::<f32>())
+                .map(|energy| 10. * (energy + 1e-10).log10())
+                .collect();
+            frames.push(log_mel);
+        }
+
+        let loudest = frames.iter().map(|frame| frame.iter().sum::<f32>() / MEL_BANDS as f32).fold(f32::MIN, f32::max);
+        let voiced: Vec<Vec<f32>> = frames.iter()
+            .filter(|frame| frame.iter().sum::<f32>() / MEL_BANDS as f32 > loudest - DYNAMIC_RANGE_DB)
+            .map(|frame| dct(frame))
+            .collect();
+        if voiced.len() < 10 {
+            return None;
+        }
+
+        let count = voiced.len() as f32;
+        let mean: Vec<f32> = (0..COEFFICIENTS)
+            .map(|i| voiced.iter().map(|frame| frame[i]).sum::<f32>() / count)
+            .collect();
+        let deviation: Vec<f32> = (0..COEFFICIENTS)
+            .map(|i| (voiced.iter().map(|frame| (frame[i] - mean[i]).powi(2)).sum::<f32>() / count).sqrt())
+            .collect();
+        Some(mean.into_iter().chain(deviation).collect())
+    }
+}
+
+fn mel_filters() -> Vec<Vec<f32>> {
+    let to_mel = |hz: f32| 2595. * (1. + hz / 700.).log10();
+    let to_hz = |mel: f32| 700. * (10f32.powf(mel / 2595.) - 1.);
+    let (low, high) = (to_mel(100.), to_mel(SAMPLE_RATE as f32 / 2. - 400.));
+    let bin = |mel: f32| to_hz(mel) * FFT_LEN as f32 / SAMPLE_RATE as f32;
+    let edges: Vec<f32> = (0..MEL_BANDS + 2)
+        .map(|i| bin(low + (high - low) * i as f32 / (MEL_BANDS + 1) as f32))
+        .collect();
+
+    (0..MEL_BANDS)
+        .map(|band| {
+            let (start, center, end) = (edges[band], edges[band + 1], edges[band + 2]);
+            (0..FFT_LEN / 2 + 1)
+                .map(|i| {
+                    let i = i as f32;
+                    if i <= start || i >= end {
+                        0.
+                    } else if i <= center {
+                        (i - start) / (center - start)
+                    } else {
+                        (end - i) / (end - center)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Cepstral coefficients 1..=COEFFICIENTS of a log mel frame
+fn dct(frame: &[f32]) -> Vec<f32> {
+    (1..=COEFFICIENTS)
+        .map(|k| {
+            frame.iter().enumerate()
+                .map(|(n, value)| value * (std::f32::consts::PI * k as f32 * (n as f32 + 0.5) / MEL_BANDS as f32).cos())
+                .sum()
+        })
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(1e-10)
+}
+
+// Clips as recorded by `assistant enroll-speaker`, mono at SAMPLE_RATE
+fn read_clip(path: &Path) -> Result<Vec<f32>> {
+    let mut reader = hound::WavReader::open(path)
+        .with_context(|| format!("Failed to open speaker clip {:?}", path))?;
+    let spec = reader.spec();
+    if spec.channels != 1 || spec.sample_rate != SAMPLE_RATE {
+        bail!("Speaker clip {:?} should be mono at {}Hz", path, SAMPLE_RATE);
+    }
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect::<Result<_, _>>()?
+        },
+    };
+    Ok(samples)
+}
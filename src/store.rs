@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Result, Context};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::usage::DayUsage;
+
+// Each one brings the database up from the version before, the version is
+// kept in user_version. Only ever add to the end
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE chats (
+        name TEXT PRIMARY KEY,
+        chat TEXT NOT NULL,
+        updated TEXT NOT NULL
+    );
+    CREATE TABLE transcripts (
+        id INTEGER PRIMARY KEY,
+        created TEXT NOT NULL,
+        wakeword TEXT NOT NULL,
+        speaker TEXT,
+        text TEXT NOT NULL,
+        addressed INTEGER NOT NULL
+    );
+    CREATE TABLE timers (
+        id INTEGER PRIMARY KEY,
+        label TEXT NOT NULL,
+        due TEXT NOT NULL
+    );
+    CREATE TABLE settings (
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE usage (
+        day TEXT PRIMARY KEY,
+        requests INTEGER NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        cost REAL NOT NULL
+    );
+    CREATE TABLE memory (
+        id INTEGER PRIMARY KEY,
+        text TEXT NOT NULL,
+        created TEXT NOT NULL,
+        embedding BLOB NOT NULL
+    );",
+    "CREATE TABLE facts (
+        id INTEGER PRIMARY KEY,
+        text TEXT NOT NULL,
+        created TEXT NOT NULL
+    );",
+];
+
+// Its text, when it was made and its embedding
+pub type StoredMemory = (String, DateTime<Utc>, Vec<f32>);
+
+// Everything kept between runs (chats, transcripts, timers, usage, memory,
+// facts and settings) in one SQLite database, with store.path
+pub struct Store {
+    connection: Mutex<Connection>,
+}
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut connection = Connection::open(path).with_context(|| format!("Failed to open database {:?}", path))?;
+        // Satellite sessions and spawned tasks write to it at the same time
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut connection).with_context(|| format!("Failed to migrate database {:?}", path))?;
+        Ok(Store { connection: Mutex::new(connection) })
+    }
+
+    pub fn chat(&self, name: &str) -> Result<Option<String>> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.query_row("SELECT chat FROM chats WHERE name = ?1", [name], |row| row.get(0)).optional()?)
+    }
+    pub fn save_chat(&self, name: &str, chat: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO chats (name, chat, updated) VALUES (?1, ?2, ?3)
+                ON CONFLICT (name) DO UPDATE SET chat = excluded.chat, updated = excluded.updated",
+            params![name, chat, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn log_transcript(&self, wakeword: &str, speaker: Option<&str>, text: &str, addressed: bool) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO transcripts (created, wakeword, speaker, text, addressed) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![Utc::now().to_rfc3339(), wakeword, speaker, text, addressed],
+        )?;
+        Ok(())
+    }
+    // Those logged before the cutoff, or all of them without one
+    pub fn remove_transcripts(&self, before: Option<DateTime<Utc>>) -> Result<usize> {
+        let connection = self.connection.lock().unwrap();
+        Ok(match before {
+            Some(before) => connection.execute("DELETE FROM transcripts WHERE created < ?1", [before.to_rfc3339()])?,
+            None => connection.execute("DELETE FROM transcripts", [])?,
+        })
+    }
+
+    pub fn timers(&self) -> Result<Vec<(i64, String, DateTime<Utc>)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT id, label, due FROM timers")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?;
+        rows.map(|row| {
+            let (id, label, due) = row?;
+            Ok((id, label, DateTime::parse_from_rfc3339(&due)?.with_timezone(&Utc)))
+        }).collect()
+    }
+    pub fn add_timer(&self, label: &str, due: DateTime<Utc>) -> Result<i64> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("INSERT INTO timers (label, due) VALUES (?1, ?2)", params![label, due.to_rfc3339()])?;
+        Ok(connection.last_insert_rowid())
+    }
+    // Whether it was still there
+    pub fn remove_timer(&self, id: i64) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.execute("DELETE FROM timers WHERE id = ?1", [id])? > 0)
+    }
+
+    pub fn setting(&self, name: &str) -> Result<Option<String>> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.query_row("SELECT value FROM settings WHERE name = ?1", [name], |row| row.get(0)).optional()?)
+    }
+    // None removes it
+    pub fn set_setting(&self, name: &str, value: Option<&str>) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        match value {
+            Some(value) => connection.execute(
+                "INSERT INTO settings (name, value) VALUES (?1, ?2)
+                    ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+                params![name, value],
+            )?,
+            None => connection.execute("DELETE FROM settings WHERE name = ?1", [name])?,
+        };
+        Ok(())
+    }
+
+    pub fn usage(&self) -> Result<BTreeMap<String, DayUsage>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT day, requests, prompt_tokens, completion_tokens, cost FROM usage")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, DayUsage {
+            requests: row.get(1)?,
+            prompt_tokens: row.get(2)?,
+            completion_tokens: row.get(3)?,
+            cost: row.get(4)?,
+        })))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+    pub fn save_usage(&self, day: &str, usage: &DayUsage) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO usage (day, requests, prompt_tokens, completion_tokens, cost) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (day) DO UPDATE SET requests = excluded.requests, prompt_tokens = excluded.prompt_tokens,
+                    completion_tokens = excluded.completion_tokens, cost = excluded.cost",
+            params![day, usage.requests, usage.prompt_tokens, usage.completion_tokens, usage.cost],
+        )?;
+        Ok(())
+    }
+
+    pub fn memories(&self) -> Result<Vec<StoredMemory>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT text, created, embedding FROM memory ORDER BY id")?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?)))?;
+        rows.map(|row| {
+            let (text, created, embedding) = row?;
+            let embedding = embedding.chunks_exact(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())).collect();
+            Ok((text, DateTime::parse_from_rfc3339(&created)?.with_timezone(&Utc), embedding))
+        }).collect()
+    }
+    pub fn add_memory(&self, text: &str, created: DateTime<Utc>, embedding: &[f32]) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let embedding: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+        connection.execute(
+            "INSERT INTO memory (text, created, embedding) VALUES (?1, ?2, ?3)",
+            params![text, created.to_rfc3339(), embedding],
+        )?;
+        Ok(())
+    }
+
+    pub fn facts(&self) -> Result<Vec<(i64, String, DateTime<Utc>)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT id, text, created FROM facts ORDER BY id")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?;
+        rows.map(|row| {
+            let (id, text, created) = row?;
+            Ok((id, text, DateTime::parse_from_rfc3339(&created)?.with_timezone(&Utc)))
+        }).collect()
+    }
+    pub fn add_fact(&self, text: &str, created: DateTime<Utc>) -> Result<i64> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("INSERT INTO facts (text, created) VALUES (?1, ?2)", params![text, created.to_rfc3339()])?;
+        Ok(connection.last_insert_rowid())
+    }
+    pub fn remove_fact(&self, id: i64) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM facts WHERE id = ?1", [id])?;
+        Ok(())
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!("The database is from a newer version (schema {}, this one knows up to {})", version, MIGRATIONS.len());
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+        println!("Migrated the database to schema {}", i + 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, Context, bail};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::chat::ToolCall;
+use crate::config::ToolsConfig;
+use crate::python;
+use crate::store::Store;
+
+// OpenAI tools API schemas for everything enabled in the config
+pub fn schemas(config: &ToolsConfig) -> Vec<Value> {
+    if !config.enabled {
+        return Vec::new();
+    }
+    let function = |name: &str, description: &str, parameters: Value| json!({
+        "type": "function",
+        "function": { "name": name, "description": description, "parameters": parameters },
+    });
+
+    let mut schemas = Vec::new();
+    if config.python.is_some() || config.python_embedded {
+        schemas.push(function("run_python", "Run Python 3 code and get back what it prints. Only the standard library is available.", json!({
+            "type": "object",
+            "properties": { "code": { "type": "string", "description": "The code to run, print the result" } },
+            "required": ["code"],
+        })));
+    }
+    if config.timers {
+        schemas.push(function("set_timer", "Start a timer, the user is told when it's done.", json!({
+            "type": "object",
+            "properties": {
+                "seconds": { "type": "integer", "description": "How long the timer runs" },
+                "label": { "type": "string", "description": "What it's for, e.g. \"pasta\"" },
+            },
+            "required": ["seconds", "label"],
+        })));
+        schemas.push(function("cancel_timer", "Cancel a running timer.", json!({
+            "type": "object",
+            "properties": { "label": { "type": "string" } },
+            "required": ["label"],
+        })));
+    }
+    for command in &config.commands {
+        let properties: serde_json::Map<String, Value> = command.parameters.iter()
+            .map(|parameter| (parameter.name.clone(), json!({ "type": "string", "description": parameter.description })))
+            .collect();
+        let required: Vec<&str> = command.parameters.iter().map(|parameter| parameter.name.as_str()).collect();
+        schemas.push(function(&command.name, &command.description, json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })));
+    }
+    schemas
+}
+
+struct Timer {
+    // Its row in the store
+    id: Option<i64>,
+    label: String,
+    due: DateTime<Utc>,
+}
+
+// Runs the tools the model asks for. Timers are kept here until the run loop
+// picks them up once they're due, and in the store so they outlast a restart.
+// Every session picks up the stored ones, whichever gets to one first says it
+pub struct Tools {
+    config: ToolsConfig,
+    store: Option<Arc<Store>>,
+    timers: Mutex<Vec<Timer>>,
+}
+impl Tools {
+    pub fn new(config: &ToolsConfig, store: Option<Arc<Store>>) -> Result<Self> {
+        let timers = match &store {
+            Some(store) => store.timers()?.into_iter().map(|(id, label, due)| Timer { id: Some(id), label, due }).collect(),
+            None => Vec::new(),
+        };
+        Ok(Tools { config: config.clone(), store, timers: Mutex::new(timers) })
+    }
+
+    // What the tool returned, failures included so the model can tell the
+    // user or try again
+    pub async fn call(&self, call: &ToolCall) -> String {
+        println!("Running tool {} {}", call.function.name, call.function.arguments);
+        let result = match serde_json::from_str::<Value>(&call.function.arguments) {
+            Ok(arguments) => self.run(&call.function.name, &arguments).await,
+            Err(err) => Err(err).context("Invalid arguments"),
+        };
+        match result {
+            Ok(output) => output,
+            Err(err) => {
+                eprintln!("Tool {} failed: {:?}", call.function.name, err);
+                format!("Error: {:#}", err)
+            }
+        }
+    }
+
+    async fn run(&self, name: &str, arguments: &Value) -> Result<String> {
+        let string = |key: &str| arguments[key].as_str().with_context(|| format!("Missing {:?}", key));
+        match name {
+            "run_python" => self.python(string("code")?).await,
+            "set_timer" if self.config.timers => {
+                let seconds = arguments["seconds"].as_u64().context("Missing \"seconds\"")?;
+                let label = string("label")?;
+                self.set_timer(label, Duration::from_secs(seconds))?;
+                Ok(format!("Timer {:?} set for {} seconds", label, seconds))
+            },
+            "cancel_timer" if self.config.timers => {
+                let label = string("label")?;
+                match self.cancel_timers(Some(label)) {
+                    0 => Ok(format!("There's no timer {:?}", label)),
+                    _ => Ok(format!("Timer {:?} cancelled", label)),
+                }
+            },
+            name => {
+                let command = self.config.commands.iter()
+                    .find(|command| command.name == name)
+                    .with_context(|| format!("Unknown tool {:?}", name))?;
+                run_command(&command.command, arguments).await
+            },
+        }
+    }
+
+    // Also what python answers are run with
+    pub async fn python(&self, code: &str) -> Result<String> {
+        let timeout = Duration::from_millis(self.config.python_timeout_ms);
+        if self.config.python_embedded {
+            return python::run(code, timeout).await;
+        }
+        let python = self.config.python.as_deref().context("Running Python is turned off, it needs tools.python set")?;
+        run_python(python, code, timeout).await
+    }
+
+    pub fn timers_enabled(&self) -> bool {
+        self.config.timers
+    }
+    pub fn set_timer(&self, label: &str, duration: Duration) -> Result<()> {
+        let due = chrono::Duration::from_std(duration).ok()
+            .and_then(|duration| Utc::now().checked_add_signed(duration))
+            .with_context(|| format!("{:?} is too long for a timer", duration))?;
+        let id = self.store.as_ref().and_then(|store| store.add_timer(label, due)
+            .map_err(|err| eprintln!("Failed to save the timer: {:?}", err))
+            .ok());
+        self.timers.lock().unwrap().push(Timer { id, label: label.to_string(), due });
+        Ok(())
+    }
+    // The ones with that label, or all of them, returning how many there were
+    pub fn cancel_timers(&self, label: Option<&str>) -> usize {
+        let mut timers = self.timers.lock().unwrap();
+        let (cancelled, running): (Vec<Timer>, Vec<Timer>) = timers.drain(..)
+            .partition(|timer| label.is_none_or(|label| timer.label.eq_ignore_ascii_case(label)));
+        *timers = running;
+        for timer in &cancelled {
+            self.remove_timer(timer);
+        }
+        cancelled.len()
+    }
+
+    // Labels of timers that just went off
+    pub fn due_timers(&self) -> Vec<String> {
+        let now = Utc::now();
+        let mut timers = self.timers.lock().unwrap();
+        let (due, running): (Vec<Timer>, Vec<Timer>) = timers.drain(..).partition(|timer| timer.due <= now);
+        *timers = running;
+        due.into_iter()
+            .filter(|timer| self.remove_timer(timer))
+            .map(|timer| timer.label)
+            .collect()
+    }
+
+    // False when another session already took it out of the store
+    fn remove_timer(&self, timer: &Timer) -> bool {
+        let (Some(store), Some(id)) = (&self.store, timer.id) else { return true };
+        store.remove_timer(id).unwrap_or_else(|err| {
+            eprintln!("Failed to remove the timer: {:?}", err);
+            true
+        })
+    }
+}
+
+pub async fn run_python(python: &str, code: &str, timeout: Duration) -> Result<String> {
+    let output = Command::new(python)
+        .arg("-c")
+        .arg(code)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, output).await
+        .with_context(|| format!("Timed out after {:?}", timeout))?
+        .with_context(|| format!("Failed to run {:?}", python))?;
+
+    let mut result = String::from_utf8_lossy(&output.stdout).trim_end().to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        result.push_str(&format!("\n{}", stderr.trim_end()));
+    }
+    Ok(result)
+}
+
+async fn run_command(command: &str, arguments: &Value) -> Result<String> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command))?;
+
+    let mut stdin = child.stdin.take().context("No stdin")?;
+    stdin.write_all(arguments.to_string().as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!("{} {}", output.status, String::from_utf8_lossy(&output.stderr).trim_end());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
@@ -0,0 +1,366 @@
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+
+use anyhow::{Result, anyhow, bail};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tokio::sync::oneshot;
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, WhisperState};
+
+use crate::audio::SAMPLE_RATE;
+use crate::config::{Config, SttBackend, WhisperConfig, WhisperStrategy};
+
+// Turns a finished command (at SAMPLE_RATE) into text
+pub trait Transcriber {
+    fn transcribe(&mut self, samples: &[f32]) -> Result<Transcription>;
+}
+
+#[derive(Debug, Default)]
+pub struct Transcription {
+    pub segments: Vec<String>,
+    // When the backend knows them
+    pub language: Option<String>,
+    // 0 to 1, how sure the backend is of the text
+    pub confidence: Option<f32>,
+    // Start and end (in seconds) of the speech in each segment, empty when the
+    // backend doesn't say
+    pub times: Vec<(f32, f32)>,
+}
+impl Transcription {
+    pub fn text(segments: Vec<String>) -> Self {
+        Transcription { segments, ..Default::default() }
+    }
+    // Adds a later piece of the same command, which is only as confident as
+    // its least confident piece
+    pub fn append(&mut self, piece: Transcription) {
+        let timed = self.is_timed() && piece.is_timed();
+        self.segments.extend(piece.segments);
+        match timed {
+            true => self.times.extend(piece.times),
+            false => self.times.clear(),
+        }
+        self.language = piece.language.or(self.language.take());
+        self.confidence = match (self.confidence, piece.confidence) {
+            (Some(confidence), Some(piece)) => Some(confidence.min(piece)),
+            (confidence, piece) => confidence.or(piece),
+        };
+    }
+    // For a transcription of audio that started `seconds` in
+    pub fn shifted(mut self, seconds: f32) -> Self {
+        for (start, end) in &mut self.times {
+            *start += seconds;
+            *end += seconds;
+        }
+        self
+    }
+    pub fn is_timed(&self) -> bool {
+        self.times.len() == self.segments.len()
+    }
+    // From the start of the first segment to the end of the last
+    pub fn speech(&self) -> Option<(f32, f32)> {
+        match (self.is_timed(), self.times.first(), self.times.last()) {
+            (true, Some((start, _)), Some((_, end))) => Some((*start, *end)),
+            _ => None,
+        }
+    }
+}
+
+type Job = (Vec<f32>, oneshot::Sender<Result<Transcription>>);
+
+// Runs the configured transcriber on its own thread, so transcribing doesn't
+// hold up the async runtime (and every other satellite's session with it)
+pub struct TranscriptionWorker {
+    jobs: Sender<Job>,
+}
+impl TranscriptionWorker {
+    pub fn new(config: &Config, whisper_ctx: Option<Arc<WhisperContext>>) -> Result<Self> {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (ready_sender, ready) = mpsc::channel();
+        let config = config.clone();
+        std::thread::spawn(move || {
+            let mut transcriber = match create(&config, whisper_ctx.as_deref()) {
+                Ok(transcriber) => transcriber,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                    return;
+                }
+            };
+            let _ = ready_sender.send(Ok(()));
+
+            for (samples, reply) in job_receiver {
+                let _ = reply.send(transcriber.transcribe(&samples));
+            }
+        });
+        ready.recv()??;
+
+        Ok(TranscriptionWorker { jobs })
+    }
+
+    pub async fn transcribe(&self, samples: Vec<f32>) -> Result<Transcription> {
+        let (reply, transcription) = oneshot::channel();
+        self.jobs.send((samples, reply)).map_err(|_| anyhow!("Transcription worker stopped"))?;
+        transcription.await?
+    }
+}
+
+// whisper_ctx is only needed (and loaded) for the whisper backend
+fn create<'a>(config: &Config, whisper_ctx: Option<&'a WhisperContext>) -> Result<Box<dyn Transcriber + 'a>> {
+    match config.stt.backend {
+        SttBackend::Whisper => {
+            let Some(whisper_ctx) = whisper_ctx else {
+                bail!("The whisper backend needs a whisper model");
+            };
+            Ok(Box::new(WhisperTranscriber {
+                state: whisper_ctx.create_state().map_err(|err| anyhow!("Failed to create whisper state: {:?}", err))?,
+                config: config.whisper.clone(),
+            }))
+        },
+        #[cfg(feature = "vosk")]
+        SttBackend::Vosk => Ok(Box::new(VoskTranscriber::new(&config.stt.vosk_model_path)?)),
+        #[cfg(not(feature = "vosk"))]
+        SttBackend::Vosk => bail!("Built without Vosk support, rebuild with --features vosk"),
+        SttBackend::Cloud => Ok(Box::new(crate::cloud_stt::CloudTranscriber::new(&config.stt.cloud)?)),
+        SttBackend::WhisperServer => Ok(Box::new(crate::whisper_server::WhisperServerTranscriber::new(&config.stt.whisper_server, &config.whisper))),
+    }
+}
+
+// GPU backend whisper was built with, if any
+const GPU_BACKEND: Option<&str> = if cfg!(feature = "cuda") {
+    Some("CUDA")
+} else if cfg!(feature = "metal") {
+    Some("Metal")
+} else if cfg!(feature = "vulkan") {
+    Some("Vulkan")
+} else {
+    None
+};
+
+pub fn load(path: &str, config: &WhisperConfig) -> Result<WhisperContext> {
+    if config.language != "en" && config.model_path.is_none() && config.model.contains(".en") {
+        eprintln!("whisper.model {:?} is English only, pick a multilingual one for {:?}", config.model, config.language);
+    }
+    let use_gpu = config.gpu && GPU_BACKEND.is_some();
+    match GPU_BACKEND {
+        Some(backend) if use_gpu => println!("Whisper running on the GPU ({})", backend),
+        Some(backend) => println!("Whisper running on the CPU, whisper.gpu is off ({} available)", backend),
+        None => println!("Whisper running on the CPU, build with --features cuda, metal or vulkan for the GPU"),
+    }
+
+    let mut params = WhisperContextParameters::new();
+    params.use_gpu(use_gpu);
+    WhisperContext::new_with_params(path, params)
+        .map_err(|err| anyhow!("Failed to load whisper model {:?}: {:?}", path, err))
+}
+
+// A stretch of audio at SAMPLE_RATE
+fn whisper(state: &mut WhisperState, samples: &[f32], config: &WhisperConfig) -> Result<Transcription> {
+    let strategy = match config.strategy {
+        WhisperStrategy::Greedy => SamplingStrategy::Greedy { best_of: config.best_of },
+        // Patience isn't implemented by whisper.cpp yet
+        WhisperStrategy::Beam => SamplingStrategy::BeamSearch { beam_size: config.beam_size, patience: -1. },
+    };
+    let mut params = FullParams::new(strategy);
+    params.set_temperature(config.temperature);
+    params.set_temperature_inc(config.temperature_inc);
+    params.set_language(Some(&config.language));
+    params.set_print_progress(false);
+    let threads = match config.threads {
+        0 => std::thread::available_parallelism().map_or(4, |threads| threads.get()),
+        threads => threads,
+    };
+    params.set_n_threads(threads as i32);
+    params.set_no_context(config.no_context);
+    params.set_single_segment(config.single_segment);
+    params.set_suppress_blank(config.suppress_blank);
+    params.set_suppress_non_speech_tokens(config.suppress_non_speech_tokens);
+    params.set_entropy_thold(config.entropy_threshold);
+    params.set_logprob_thold(config.logprob_threshold);
+    params.set_token_timestamps(true);
+    if !config.vocabulary.is_empty() {
+        // Reads like earlier speech, so whisper spells the words the same way
+        params.set_initial_prompt(&format!("{}.", config.vocabulary.join(", ")));
+    }
+    state.full(params, samples).map_err(|err| anyhow!("Failed to run whisper model: {:?}", err))?;
+
+    let num_segments = state.full_n_segments().map_err(|err| anyhow!("Failed to get whisper segment count: {:?}", err))?;
+    let segments = (0..num_segments)
+        .map(|i| state.full_get_segment_text(i).map_err(|err| anyhow!("Failed to get whisper segment: {:?}", err)))
+        .collect::<Result<_>>()?;
+
+    // Average probability of the text tokens, special tokens like [_BEG_] and
+    // timestamps are always confident. The text tokens' timestamps also pin
+    // down the speech much closer than the segment's own
+    let mut probabilities = Vec::new();
+    let mut times = Vec::new();
+    for segment in 0..num_segments {
+        let mut speech: Option<(i64, i64)> = None;
+        for token in 0..state.full_n_tokens(segment).unwrap_or(0) {
+            let is_special = state.full_get_token_text(segment, token).map_or(true, |text| text.starts_with("[_"));
+            if is_special {
+                continue;
+            }
+            if let Ok(probability) = state.full_get_token_prob(segment, token) {
+                probabilities.push(probability);
+            }
+            if let Ok(data) = state.full_get_token_data(segment, token) {
+                speech = Some(speech.map_or((data.t0, data.t1), |(start, end)| (start.min(data.t0), end.max(data.t1))));
+            }
+        }
+        let (start, end) = match speech {
+            Some(speech) => speech,
+            None => (
+                state.full_get_segment_t0(segment).unwrap_or(0),
+                state.full_get_segment_t1(segment).unwrap_or(0),
+            ),
+        };
+        // In 10ms steps
+        times.push((start as f32 / 100., end as f32 / 100.));
+    }
+    let confidence = (!probabilities.is_empty())
+        .then(|| probabilities.iter().sum::<f32>() / probabilities.len() as f32);
+
+    Ok(Transcription {
+        segments,
+        // Mostly useful with language = "auto"
+        language: state.full_lang_id().ok().and_then(whisper_rs::get_lang_str).map(str::to_string),
+        confidence,
+        times,
+    })
+}
+
+pub struct WhisperTranscriber<'a> {
+    state: WhisperState<'a>,
+    config: WhisperConfig,
+}
+impl Transcriber for WhisperTranscriber<'_> {
+    fn transcribe(&mut self, samples: &[f32]) -> Result<Transcription> {
+        whisper(&mut self.state, samples, &self.config)
+    }
+}
+
+// Much lighter than whisper and much less accurate, for small devices
+#[cfg(feature = "vosk")]
+pub struct VoskTranscriber {
+    recognizer: vosk::Recognizer,
+}
+#[cfg(feature = "vosk")]
+impl VoskTranscriber {
+    pub fn new(model_path: &str) -> Result<Self> {
+        vosk::set_log_level(vosk::LogLevel::Warn);
+        let model = vosk::Model::new(model_path)
+            .ok_or_else(|| anyhow!("Failed to load Vosk model {:?}", model_path))?;
+        let recognizer = vosk::Recognizer::new(&model, SAMPLE_RATE as f32)
+            .ok_or_else(|| anyhow!("Failed to create Vosk recognizer"))?;
+        Ok(VoskTranscriber { recognizer })
+    }
+}
+#[cfg(feature = "vosk")]
+impl Transcriber for VoskTranscriber {
+    fn transcribe(&mut self, samples: &[f32]) -> Result<Transcription> {
+        let samples: Vec<i16> = samples.iter()
+            .map(|sample| (sample.clamp(-1., 1.) * i16::MAX as f32) as i16)
+            .collect();
+        self.recognizer.accept_waveform(&samples)?;
+        // Also resets the recognizer for the next command
+        let text = self.recognizer.final_result().single().map(|result| result.text.to_string());
+        Ok(Transcription::text(text.into_iter().collect()))
+    }
+}
+
+// Transcribes a command piece by piece while it's still being spoken, cutting
+// it at pauses, so once it's finished only the tail is left to transcribe
+pub struct StreamingTranscriber {
+    // Command, where the piece starts in it, and the piece
+    jobs: Sender<(usize, usize, Vec<f32>)>,
+    results: UnboundedReceiver<(usize, Result<Transcription>)>,
+    // Results from earlier commands still in flight are dropped
+    command: usize,
+    in_flight: usize,
+    committed: usize,
+    transcription: Transcription,
+    failed: bool,
+    min_chunk: usize,
+}
+impl StreamingTranscriber {
+    pub fn new(whisper_ctx: Arc<WhisperContext>, config: &WhisperConfig) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<(usize, usize, Vec<f32>)>();
+        let (result_sender, results) = unbounded_channel();
+        let thread_config = config.clone();
+        std::thread::spawn(move || {
+            let mut state = whisper_ctx.create_state().expect("Failed to create state");
+            for (command, offset, samples) in job_receiver {
+                let piece = whisper(&mut state, &samples, &thread_config)
+                    .map(|piece| piece.shifted(offset as f32 / SAMPLE_RATE as f32));
+                if result_sender.send((command, piece)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        StreamingTranscriber {
+            jobs,
+            results,
+            command: 0,
+            in_flight: 0,
+            committed: 0,
+            transcription: Transcription::default(),
+            failed: false,
+            min_chunk: SAMPLE_RATE as usize * config.streaming_min_chunk_ms as usize / 1000,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.command += 1;
+        self.in_flight = 0;
+        self.committed = 0;
+        self.transcription = Transcription::default();
+        self.failed = false;
+    }
+
+    // The user paused, send off everything since the last pause if it's long
+    // enough for whisper to do well on
+    pub fn pause(&mut self, utterance: &[f32]) {
+        self.collect();
+        if utterance.len() < self.committed + self.min_chunk {
+            return;
+        }
+        if self.jobs.send((self.command, self.committed, utterance[self.committed..].to_vec())).is_ok() {
+            self.in_flight += 1;
+            self.committed = utterance.len();
+        }
+    }
+
+    // Waits for the pieces in flight, returning their transcription and how
+    // much of the utterance they covered
+    pub async fn finish(&mut self) -> Result<(Transcription, usize)> {
+        while self.in_flight > 0 {
+            let (command, piece) = self.results.recv().await.ok_or_else(|| anyhow!("The streaming transcriber stopped"))?;
+            self.add(command, piece);
+        }
+        let failed = self.failed;
+        let finished = (std::mem::take(&mut self.transcription), self.committed);
+        self.reset();
+        if failed {
+            bail!("A piece of the command failed to transcribe");
+        }
+        Ok(finished)
+    }
+
+    fn collect(&mut self) {
+        while let Ok((command, piece)) = self.results.try_recv() {
+            self.add(command, piece);
+        }
+    }
+    fn add(&mut self, command: usize, piece: Result<Transcription>) {
+        if command != self.command {
+            return;
+        }
+        self.in_flight -= 1;
+        match piece {
+            Ok(piece) => self.transcription.append(piece),
+            Err(err) => {
+                eprintln!("Streaming transcription failed: {:?}", err);
+                self.failed = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use anyhow::{Result, Context};
+use regex::{Regex, RegexBuilder};
+
+use crate::config::{TranscriptConfig, AddressConfig};
+
+// Whisper output kept in two forms: the original casing (proper nouns,
+// acronyms) for the LLM and display, and a normalized copy for matching
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    original: String,
+    normalized: String,
+    // Language whisper heard, when it isn't English
+    language: Option<String>,
+}
+impl Transcript {
+    pub fn new(segments: &[String]) -> Self {
+        let original = segments.iter()
+            .map(|segment| strip_brackets(segment.trim()))
+            .map(|segment| segment.trim().to_string())
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let normalized = original.to_lowercase();
+
+        Transcript { original, normalized, language: None }
+    }
+    pub fn with_language(mut self, language: Option<&str>) -> Self {
+        self.language = language.filter(|language| *language != "en").map(str::to_string);
+        self
+    }
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+    pub fn normalized(&self) -> &str {
+        &self.normalized
+    }
+    pub fn for_llm(&self, config: &TranscriptConfig) -> String {
+        config.llm_case.apply(&self.original)
+    }
+    pub fn for_display(&self, config: &TranscriptConfig) -> String {
+        config.display_case.apply(&self.original)
+    }
+}
+
+// Checks whether a transcript starts by addressing the assistant
+pub struct AddressMatcher {
+    regex: Regex,
+}
+impl AddressMatcher {
+    pub fn new(config: &AddressConfig) -> Result<Self> {
+        let alternatives: Vec<String> = config.terms.iter()
+            .map(|term| regex::escape(term))
+            .chain(config.aliases.iter().cloned())
+            .collect();
+        let regex = RegexBuilder::new(&format!("^(?:{})", alternatives.join("|")))
+            .case_insensitive(true)
+            .build()?;
+
+        Ok(AddressMatcher { regex })
+    }
+    pub fn is_match(&self, transcript: &Transcript) -> bool {
+        self.regex.is_match(transcript.normalized())
+    }
+}
+
+// Fixes up whisper's segments before they're used, going by the transcript
+// config
+pub struct Rewriter {
+    numbers_to_digits: bool,
+    rules: Vec<(Regex, String)>,
+}
+impl Rewriter {
+    pub fn new(config: &TranscriptConfig) -> Result<Self> {
+        let rules = config.rewrites.iter()
+            .map(|rule| {
+                let pattern = match rule.regex {
+                    true => rule.from.clone(),
+                    false => format!(r"\b{}\b", regex::escape(&rule.from)),
+                };
+                let regex = RegexBuilder::new(&pattern)
+                    .case_insensitive(!rule.regex)
+                    .build()
+                    .with_context(|| format!("Invalid rewrite {:?}", rule.from))?;
+                // Plain words are replaced as they are, "$" and all
+                let to = match rule.regex {
+                    true => rule.to.clone(),
+                    false => rule.to.replace('$', "$$"),
+                };
+                Ok((regex, to))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Rewriter { numbers_to_digits: config.numbers_to_digits, rules })
+    }
+    pub fn apply(&self, segments: &[String]) -> Vec<String> {
+        segments.iter()
+            .map(|segment| {
+                let mut segment = match self.numbers_to_digits {
+                    true => numbers_to_digits(segment),
+                    false => segment.clone(),
+                };
+                for (regex, to) in &self.rules {
+                    segment = regex.replace_all(&segment, to.as_str()).to_string();
+                }
+                segment
+            })
+            .collect()
+    }
+}
+
+fn number_value(word: &str) -> Option<u64> {
+    const SMALL: [&str; 20] = [
+        "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+        "eleven", "twelve", "thirteen", "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen",
+    ];
+    const TENS: [&str; 8] = ["twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety"];
+    if let Some((tens, unit)) = word.split_once('-') {
+        return match (number_value(tens), number_value(unit)) {
+            (Some(tens), Some(unit)) if tens >= 20 && tens % 10 == 0 && (1..10).contains(&unit) => Some(tens + unit),
+            _ => None,
+        };
+    }
+    SMALL.iter().position(|small| *small == word).map(|value| value as u64)
+        .or_else(|| TENS.iter().position(|tens| *tens == word).map(|value| (value as u64 + 2) * 10))
+}
+
+// "twenty five" -> "25", "two hundred and one" -> "201". Separate numbers in a
+// row ("one two") stay separate
+pub fn numbers_to_digits(input: &str) -> String {
+    let mut words: Vec<String> = Vec::new();
+    // The number so far: finished thousands/millions, and the part below them
+    let mut number: Option<(u64, u64)> = None;
+    let mut pending_and = false;
+
+    let finish = |number: &mut Option<(u64, u64)>, pending_and: &mut bool, words: &mut Vec<String>| {
+        if let Some((total, current)) = number.take() {
+            words.push((total + current).to_string());
+        }
+        if std::mem::replace(pending_and, false) {
+            words.push("and".to_string());
+        }
+    };
+    for word in input.split_whitespace() {
+        let trimmed = word.trim_end_matches(|c: char| c.is_ascii_punctuation());
+        let punctuation = &word[trimmed.len()..];
+        let lowercase = trimmed.to_lowercase();
+
+        if lowercase == "and" && number.is_some() && punctuation.is_empty() {
+            pending_and = true;
+            continue;
+        }
+        let joined = match (lowercase.as_str(), number_value(&lowercase), number) {
+            ("hundred", _, Some((total, current))) if current % 100 != 0 && current < 100 => Some((total, current * 100)),
+            ("thousand" | "million", _, Some((total, current))) if current > 0 => {
+                let scale = if lowercase == "thousand" { 1_000 } else { 1_000_000 };
+                Some((total + current * scale, 0))
+            },
+            // Another number only continues this one if its place is free
+            (_, Some(value), Some((total, current))) if value > 0 && total + current > 0 && (
+                current % 100 == 0 ||
+                (current % 100 >= 20 && current % 10 == 0 && value < 10)
+            ) => Some((total, current + value)),
+            _ => None,
+        };
+        match (joined, number_value(&lowercase)) {
+            (Some(joined), _) => {
+                number = Some(joined);
+                pending_and = false;
+            },
+            (None, Some(value)) => {
+                finish(&mut number, &mut pending_and, &mut words);
+                number = Some((0, value));
+            },
+            (None, None) => {
+                finish(&mut number, &mut pending_and, &mut words);
+                words.push(word.to_string());
+                continue;
+            },
+        }
+        if !punctuation.is_empty() {
+            finish(&mut number, &mut pending_and, &mut words);
+            words.last_mut().unwrap().push_str(punctuation);
+        }
+    }
+    finish(&mut number, &mut pending_and, &mut words);
+    words.join(" ")
+}
+
+fn strip_brackets(input: &str) -> String {
+    let re = Regex::new(r"[\[\(].+?[\]\)]").expect("Invalid regex");
+    re.replace_all(input, "").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_original_casing_for_llm() {
+        let transcript = Transcript::new(&[" Computer,".to_string(), "what does NASA do".to_string()]);
+        assert_eq!(transcript.normalized(), "computer, what does nasa do");
+        assert_eq!(transcript.for_llm(&TranscriptConfig::default()), "Computer, what does NASA do");
+    }
+
+    #[test]
+    fn matches_address_terms_and_aliases() {
+        let transcript = |text: &str| Transcript::new(&[text.to_string()]);
+        let matcher = AddressMatcher::new(&AddressConfig::default()).unwrap();
+        for text in ["Peter", "the peter, lights off", "COMPUTER, what time is it"] {
+            assert!(matcher.is_match(&transcript(text)), "{:?}", text);
+        }
+        assert!(!matcher.is_match(&transcript("compute this for me")));
+        assert!(!matcher.is_match(&transcript("hey jarvis")));
+
+        let mut config = AddressConfig::default();
+        config.aliases.push("hey jarvis".to_string());
+        let matcher = AddressMatcher::new(&config).unwrap();
+        assert!(matcher.is_match(&transcript("Hey Jarvis, play music")));
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::{Result, bail};
+use tokio::process::Command;
+
+use crate::config::{TtsConfig, PerformanceConfig};
+
+// Synthesize speech with mimic into WAV data, played back through the regular
+// output so it can be cancelled from the microphone input
+pub async fn synthesize(text: &str, config: &TtsConfig, performance: &PerformanceConfig) -> Result<Vec<u8>> {
+    // One per call, so answers being synthesized at the same time (satellites,
+    // announcements) don't overwrite each other
+    let file = tempfile::Builder::new().prefix("assistant_tts").suffix(".wav").tempfile()?;
+    let status = tts_command(&config.mimic_path, performance.tts_niceness)
+        .arg("-voice").arg(&config.voice)
+        .arg("--setf").arg(format!("duration_stretch={}", config.duration_stretch))
+        .arg("--setf").arg(format!("int_f0_target_mean={}", config.f0_target_mean))
+        .arg("-o").arg(file.path())
+        .arg(format!(r#""{}""#, text))
+        .status()
+        .await?;
+    if !status.success() {
+        bail!("Mimic failed with {}", status);
+    }
+
+    Ok(std::fs::read(file.path())?)
+}
+
+#[cfg(unix)]
+fn tts_command(program: &str, niceness: Option<i32>) -> Command {
+    match niceness {
+        Some(niceness) => {
+            let mut command = Command::new("nice");
+            command.arg("-n").arg(niceness.to_string()).arg(program);
+            command
+        },
+        None => Command::new(program),
+    }
+}
+#[cfg(windows)]
+fn tts_command(program: &str, niceness: Option<i32>) -> Command {
+    const IDLE_PRIORITY_CLASS: u32 = 0x00000040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x00004000;
+
+    let mut command = Command::new(program);
+    match niceness {
+        Some(niceness) if niceness >= 10 => { command.creation_flags(IDLE_PRIORITY_CLASS); },
+        Some(niceness) if niceness > 0 => { command.creation_flags(BELOW_NORMAL_PRIORITY_CLASS); },
+        _ => {}
+    }
+    command
+}
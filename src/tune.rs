@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::audio::Capture;
+use crate::config::Config;
+use crate::wakeword::{self, WakeDetector};
+
+// Low enough that near-misses show up as detections too
+const TUNING_THRESHOLD: f32 = 0.1;
+// How long to wait for each spoken wakeword
+const ATTEMPT_SECONDS: u64 = 3;
+
+// Prints wakeword scores as they come in, collecting the best score for each
+// time the wakeword is said and every score from a stretch of background
+// noise, then suggests a threshold that separates the two
+pub async fn tune(config: &Config, count: usize, background_seconds: u64) -> Result<()> {
+    let mut tuning = config.wakeword.clone();
+    tuning.threshold = TUNING_THRESHOLD;
+    tuning.avg_threshold = 0.;
+    let mut detector = wakeword::create(&tuning, &[])?;
+
+    let host = cpal::default_host();
+    let mut capture = Capture::start(&host, &config.audio, config.debug.verbosity)?;
+    let mut pending: Vec<f32> = Vec::new();
+
+    let mut true_scores = Vec::new();
+    for attempt in 1..=count {
+        println!("({}/{}) Say {:?}", attempt, count, config.wakeword.name);
+        capture.consumer.clear();
+        let best = listen(&mut capture, detector.as_mut(), &mut pending, config, ATTEMPT_SECONDS).await
+            .into_iter()
+            .reduce(f32::max);
+        match best {
+            Some(score) => true_scores.push(score),
+            None => println!("Missed, scored below {}", TUNING_THRESHOLD),
+        }
+    }
+
+    println!("Now stay quiet, talk or play something that sets it off by mistake for {}s", background_seconds);
+    capture.consumer.clear();
+    let false_scores = listen(&mut capture, detector.as_mut(), &mut pending, config, background_seconds).await;
+
+    let lowest_true = true_scores.iter().copied().reduce(f32::min);
+    let highest_false = false_scores.iter().copied().reduce(f32::max);
+    println!("Wakeword: {} of {} heard, lowest score {:?}", true_scores.len(), count, lowest_true);
+    println!("Background: {} activations, highest score {:?}", false_scores.len(), highest_false);
+
+    match (lowest_true, highest_false) {
+        (None, _) => println!("Never heard the wakeword, try recording new clips"),
+        (Some(lowest), None) => println!("Suggested wakeword.threshold: {:.2}", (lowest - 0.05).max(TUNING_THRESHOLD)),
+        (Some(lowest), Some(highest)) if lowest > highest => {
+            println!("Suggested wakeword.threshold: {:.2}", (lowest + highest) / 2.);
+        },
+        (Some(lowest), Some(highest)) => {
+            println!("The wakeword ({:.2}) and background ({:.2}) scores overlap, more or better clips should help", lowest, highest);
+            println!("Suggested wakeword.threshold: {:.2} (fewer false activations, more misses)", highest + 0.01);
+        },
+    }
+    Ok(())
+}
+
+// Scores of every detection heard in the next `seconds`
+async fn listen(capture: &mut Capture, detector: &mut dyn WakeDetector, pending: &mut Vec<f32>, config: &Config, seconds: u64) -> Vec<f32> {
+    let mut scores = Vec::new();
+    let frame_length = detector.samples_per_frame();
+    let until = Instant::now() + Duration::from_secs(seconds);
+    pending.clear();
+    while Instant::now() < until {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        pending.extend(capture.consumer.pop_iter());
+        while pending.len() >= frame_length {
+            let frame: Vec<f32> = pending.drain(..frame_length).collect();
+            if let Some(detection) = detector.process(&frame) {
+                let verdict = if detection.score >= config.wakeword.threshold { "detected" } else { "near-miss" };
+                println!("Score {:.3} (avg {:.3}), {} at threshold {}", detection.score, detection.avg_score, verdict, config.wakeword.threshold);
+                scores.push(detection.score);
+            }
+        }
+    }
+    scores
+}
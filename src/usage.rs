@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::chat::{Backend, Entry};
+use crate::config::UsageConfig;
+use crate::llm::{BoxFuture, Completion, LanguageModel, RequestOptions, Usage};
+use crate::store::Store;
+
+// USD per million prompt and completion tokens, matched by model name prefix
+// (the longest one wins). llm.*.prompt_price and completion_price override it
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.),
+    ("gpt-4.1-nano", 0.1, 0.4),
+    ("gpt-4.1-mini", 0.4, 1.6),
+    ("gpt-4.1", 2., 8.),
+    ("gpt-4-turbo", 10., 30.),
+    ("gpt-4", 30., 60.),
+    ("claude-3-haiku", 0.25, 1.25),
+    ("claude-3-5-haiku", 0.8, 4.),
+    ("claude-3-5-sonnet", 3., 15.),
+    ("claude-3-7-sonnet", 3., 15.),
+    ("claude-sonnet-4", 3., 15.),
+    ("claude-3-opus", 15., 75.),
+    ("claude-opus-4", 15., 75.),
+    ("text-embedding-3-small", 0.02, 0.),
+    ("text-embedding-3-large", 0.13, 0.),
+    ("text-embedding-ada-002", 0.1, 0.),
+];
+
+// What a request cost, free for local backends and unknown models
+pub fn cost(backend: &Backend, usage: &Usage) -> f64 {
+    let table = PRICES.iter()
+        .filter(|(prefix, _, _)| backend.model.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|&(_, prompt, completion)| (prompt, completion));
+    let prompt = backend.prompt_price.or(table.map(|prices| prices.0)).unwrap_or(0.);
+    let completion = backend.completion_price.or(table.map(|prices| prices.1)).unwrap_or(0.);
+    (usage.prompt_tokens as f64 * prompt + usage.completion_tokens as f64 * completion) / 1e6
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DayUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+// Totals per day (UTC), saved to the store or usage.path after every request
+pub struct UsageTracker {
+    path: Option<PathBuf>,
+    store: Option<Arc<Store>>,
+    days: Mutex<BTreeMap<String, DayUsage>>,
+}
+impl UsageTracker {
+    pub fn new(config: &UsageConfig, store: Option<Arc<Store>>) -> Result<Self> {
+        let path = config.path.as_ref().map(PathBuf::from);
+        let days = match (&store, &path) {
+            (Some(store), _) => store.usage()?,
+            (None, Some(path)) if path.exists() => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read usage {:?}", path))?;
+                serde_json::from_str(&contents).with_context(|| format!("Failed to parse usage {:?}", path))?
+            },
+            _ => BTreeMap::new(),
+        };
+        Ok(UsageTracker { path, store, days: Mutex::new(days) })
+    }
+
+    pub fn record(&self, backend: &Backend, usage: &Usage) {
+        let cost = cost(backend, usage);
+        let mut days = self.days.lock().unwrap();
+        let day = today();
+        let today = days.entry(day.clone()).or_default();
+        today.requests += 1;
+        today.prompt_tokens += usage.prompt_tokens;
+        today.completion_tokens += usage.completion_tokens;
+        today.cost += cost;
+        println!("{} used {} prompt and {} completion tokens (${:.4}, ${:.2} today)",
+            backend.name, usage.prompt_tokens, usage.completion_tokens, cost, today.cost);
+
+        if let Some(store) = &self.store {
+            if let Err(err) = store.save_usage(&day, today) {
+                eprintln!("Failed to save usage: {:?}", err);
+            }
+        } else if let Some(path) = &self.path {
+            let saved = serde_json::to_string_pretty(&*days).map_err(anyhow::Error::from)
+                .and_then(|json| std::fs::write(path, json).map_err(anyhow::Error::from));
+            if let Err(err) = saved {
+                eprintln!("Failed to save usage to {:?}: {:?}", path, err);
+            }
+        }
+    }
+
+    pub fn today(&self) -> DayUsage {
+        self.days.lock().unwrap().get(&today()).cloned().unwrap_or_default()
+    }
+}
+
+// Records the usage of every request that goes through it
+pub struct Metered {
+    pub model: Box<dyn LanguageModel>,
+    pub backend: Backend,
+    pub usage: std::sync::Arc<UsageTracker>,
+}
+impl Metered {
+    async fn complete(&self, messages: &[Entry], options: &RequestOptions, deltas: Option<UnboundedSender<String>>) -> Result<(Entry, Usage)> {
+        let (completion, usage) = self.model.request(messages, options, deltas).await?;
+        self.usage.record(&self.backend, &usage);
+        Ok((completion, usage))
+    }
+}
+impl LanguageModel for Metered {
+    fn request<'a>(&'a self, messages: &'a [Entry], options: &'a RequestOptions, deltas: Option<UnboundedSender<String>>) -> Completion<'a> {
+        Box::pin(self.complete(messages, options, deltas))
+    }
+    fn prepare(&self) -> BoxFuture<'_, Result<()>> {
+        self.model.prepare()
+    }
+}
+
+// As YYYY-MM-DD in UTC
+pub fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() / 86400) as i64;
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,212 @@
+use anyhow::{Result, anyhow, bail};
+use webrtc_vad::Vad;
+
+use crate::audio::{SAMPLE_RATE, resample_linear};
+use crate::config::{VadAggressiveness, VadBackend, VadConfig};
+
+// Decides whether the user is still talking, fed every new (processed) input
+// sample at SAMPLE_RATE
+pub trait VoiceDetector {
+    // Whether the most recent audio contains speech
+    fn process(&mut self, samples: &[f32]) -> Result<bool>;
+}
+
+pub fn create(config: &VadConfig) -> Result<Box<dyn VoiceDetector>> {
+    match config.backend {
+        VadBackend::Webrtc => Ok(Box::new(WebrtcVad::new(config)?)),
+        VadBackend::Energy => Ok(Box::new(EnergyVad::new(config)?)),
+        #[cfg(feature = "silero")]
+        VadBackend::Silero => Ok(Box::new(SileroVad::new(config)?)),
+        #[cfg(not(feature = "silero"))]
+        VadBackend::Silero => bail!("Built without Silero VAD support, rebuild with --features silero"),
+    }
+}
+
+// Cuts the input into frame_ms frames at the VAD's sample rate
+struct FrameAssembler {
+    pending: Vec<f32>,
+    input_length: usize,
+    frame_length: usize,
+    sample_rate: u32,
+}
+impl FrameAssembler {
+    fn new(config: &VadConfig) -> Result<Self> {
+        if ![10, 20, 30].contains(&config.frame_ms) {
+            bail!("VAD frames must be 10, 20 or 30 ms, not {}", config.frame_ms);
+        }
+        if ![8000, 16000, 32000, 48000].contains(&config.sample_rate) {
+            bail!("VAD sample rate must be 8000, 16000, 32000 or 48000, not {}", config.sample_rate);
+        }
+        Ok(FrameAssembler {
+            pending: Vec::new(),
+            input_length: (SAMPLE_RATE * config.frame_ms / 1000) as usize,
+            frame_length: (config.sample_rate * config.frame_ms / 1000) as usize,
+            sample_rate: config.sample_rate,
+        })
+    }
+    fn push(&mut self, samples: &[f32]) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(samples);
+        let mut frames = Vec::new();
+        while self.pending.len() >= self.input_length {
+            let input: Vec<f32> = self.pending.drain(..self.input_length).collect();
+            let mut frame = resample_linear(&input, SAMPLE_RATE, self.sample_rate);
+            frame.resize(self.frame_length, *frame.last().unwrap_or(&0.));
+            frames.push(frame);
+        }
+        frames
+    }
+}
+
+pub struct WebrtcVad {
+    vad: Vad,
+    frames: FrameAssembler,
+    voice: bool,
+}
+impl WebrtcVad {
+    pub fn new(config: &VadConfig) -> Result<Self> {
+        let mode = match config.webrtc_aggressiveness {
+            VadAggressiveness::Quality => webrtc_vad::VadMode::Quality,
+            VadAggressiveness::LowBitrate => webrtc_vad::VadMode::LowBitrate,
+            VadAggressiveness::Aggressive => webrtc_vad::VadMode::Aggressive,
+            VadAggressiveness::VeryAggressive => webrtc_vad::VadMode::VeryAggressive,
+        };
+        let frames = FrameAssembler::new(config)?;
+        let rate = match frames.sample_rate {
+            8000 => webrtc_vad::SampleRate::Rate8kHz,
+            16000 => webrtc_vad::SampleRate::Rate16kHz,
+            32000 => webrtc_vad::SampleRate::Rate32kHz,
+            _ => webrtc_vad::SampleRate::Rate48kHz,
+        };
+
+        Ok(WebrtcVad {
+            vad: Vad::new_with_rate_and_mode(rate, mode),
+            frames,
+            voice: false,
+        })
+    }
+}
+impl VoiceDetector for WebrtcVad {
+    fn process(&mut self, samples: &[f32]) -> Result<bool> {
+        for frame in self.frames.push(samples) {
+            let frame: Vec<i16> = frame.into_iter()
+                .map(|sample| (sample.clamp(-1., 1.) * i16::MAX as f32) as i16)
+                .collect();
+            self.voice = self.vad.is_voice_segment(&frame).map_err(|_| anyhow!("VAD failed"))?;
+        }
+        Ok(self.voice)
+    }
+}
+
+// Frame RMS compared against a noise floor, measured at startup and then slowly
+// tracked while nobody is talking
+pub struct EnergyVad {
+    frames: FrameAssembler,
+    calibration_frames: usize,
+    calibration: Vec<f32>,
+    noise_floor: Option<f32>,
+    ratio: f32,
+    voice: bool,
+}
+impl EnergyVad {
+    pub fn new(config: &VadConfig) -> Result<Self> {
+        Ok(EnergyVad {
+            frames: FrameAssembler::new(config)?,
+            calibration_frames: (config.energy_calibration_ms as usize / config.frame_ms as usize).max(1),
+            calibration: Vec::new(),
+            noise_floor: None,
+            ratio: config.energy_threshold_ratio,
+            voice: false,
+        })
+    }
+}
+impl VoiceDetector for EnergyVad {
+    fn process(&mut self, samples: &[f32]) -> Result<bool> {
+        for frame in self.frames.push(samples) {
+            let energy = (frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32).sqrt();
+
+            match &mut self.noise_floor {
+                None => {
+                    self.calibration.push(energy);
+                    if self.calibration.len() >= self.calibration_frames {
+                        // A floor of exactly 0 (digital silence) would make everything speech
+                        let floor = (self.calibration.iter().sum::<f32>() / self.calibration.len() as f32).max(1e-4);
+                        println!("Noise floor: {:.5}", floor);
+                        self.noise_floor = Some(floor);
+                    }
+                },
+                Some(floor) => {
+                    self.voice = energy > *floor * self.ratio;
+                    if !self.voice {
+                        *floor = (*floor * 0.995 + energy * 0.005).max(1e-4);
+                    }
+                },
+            }
+        }
+        Ok(self.voice)
+    }
+}
+
+// Silero v5 takes 512 sample chunks at 16 kHz, with the end of the previous
+// chunk prepended as context
+#[cfg(feature = "silero")]
+const SILERO_CHUNK_LENGTH: usize = 512;
+#[cfg(feature = "silero")]
+const SILERO_CONTEXT_LENGTH: usize = 64;
+
+#[cfg(feature = "silero")]
+pub struct SileroVad {
+    session: ort::session::Session,
+    state: Vec<f32>,
+    context: Vec<f32>,
+    pending: Vec<f32>,
+    threshold: f32,
+    voice: bool,
+}
+#[cfg(feature = "silero")]
+impl SileroVad {
+    pub fn new(config: &VadConfig) -> Result<Self> {
+        let session = ort::session::Session::builder()?
+            .commit_from_file(&config.silero_model_path)
+            .map_err(|err| anyhow!("Failed to load Silero VAD model {:?}: {}", config.silero_model_path, err))?;
+
+        Ok(SileroVad {
+            session,
+            state: vec![0.; 2 * 128],
+            context: vec![0.; SILERO_CONTEXT_LENGTH],
+            pending: Vec::new(),
+            threshold: config.silero_threshold,
+            voice: false,
+        })
+    }
+}
+#[cfg(feature = "silero")]
+impl VoiceDetector for SileroVad {
+    fn process(&mut self, samples: &[f32]) -> Result<bool> {
+        use ort::value::Tensor;
+
+        self.pending.extend_from_slice(samples);
+        while self.pending.len() >= SILERO_CHUNK_LENGTH {
+            let chunk: Vec<f32> = self.pending.drain(..SILERO_CHUNK_LENGTH).collect();
+            let input: Vec<f32> = self.context.iter().chain(chunk.iter()).copied().collect();
+            self.context = chunk[SILERO_CHUNK_LENGTH - SILERO_CONTEXT_LENGTH..].to_vec();
+
+            let outputs = self.session.run(ort::inputs![
+                "input" => Tensor::from_array(([1, input.len()], input))?,
+                "state" => Tensor::from_array(([2, 1, 128], self.state.clone()))?,
+                "sr" => Tensor::from_array(((), vec![SAMPLE_RATE as i64]))?,
+            ])?;
+            let probability = outputs["output"].try_extract_tensor::<f32>()?.1[0];
+            let state = outputs["stateN"].try_extract_tensor::<f32>()?.1.to_vec();
+            drop(outputs);
+            self.state = state;
+
+            // A little hysteresis so speech doesn't flicker on and off around the threshold
+            self.voice = if self.voice {
+                probability >= self.threshold - 0.15
+            } else {
+                probability >= self.threshold
+            };
+        }
+        Ok(self.voice)
+    }
+}
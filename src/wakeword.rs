@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use anyhow::{Result, Context, anyhow, bail};
+use rustpotter::{Rustpotter, RustpotterConfig, RustpotterDetection, ScoreMode, Wakeword};
+
+use crate::config::{PersonaConfig, PlaybackCommandConfig, WakewordBackend, WakewordConfig, WakewordScoreMode};
+
+#[derive(Debug, Clone)]
+pub struct Detection {
+    pub name: String,
+    pub score: f32,
+    pub avg_score: f32,
+    // Number of frames that scored, the detection fires this many frames
+    // after the end of the wakeword
+    pub counter: usize,
+}
+impl From<&RustpotterDetection> for Detection {
+    fn from(detection: &RustpotterDetection) -> Self {
+        Detection {
+            name: detection.name.clone(),
+            score: detection.score,
+            avg_score: detection.avg_score,
+            counter: detection.counter,
+        }
+    }
+}
+
+// Detectors are fed consecutive samples_per_frame() long frames
+pub trait WakeDetector {
+    fn samples_per_frame(&self) -> usize;
+    fn process(&mut self, frame: &[f32]) -> Option<Detection>;
+    // Detection in progress that hasn't crossed the threshold yet
+    fn partial(&self) -> Option<Detection> {
+        None
+    }
+}
+
+// The main wakeword and every persona's, on the configured backend
+pub fn create(config: &WakewordConfig, personas: &[PersonaConfig]) -> Result<Box<dyn WakeDetector>> {
+    let wakewords = std::iter::once((config.name.clone(), config.source()))
+        .chain(personas.iter().map(|persona| (persona.wakeword.clone(), persona.source())))
+        .collect();
+    create_backend(wakewords, config)
+}
+
+// Playback command words on the same backend, detections carry the word.
+// None when there aren't any
+pub fn create_commands(config: &WakewordConfig, commands: &[PlaybackCommandConfig]) -> Result<Option<Box<dyn WakeDetector>>> {
+    if commands.is_empty() {
+        return Ok(None);
+    }
+    let words = commands.iter().map(|command| (command.word.clone(), command.source())).collect();
+    Ok(Some(create_backend(words, config)?))
+}
+
+fn create_backend(wakewords: Vec<(String, WakewordSource)>, config: &WakewordConfig) -> Result<Box<dyn WakeDetector>> {
+    match config.backend {
+        WakewordBackend::Rustpotter => Ok(Box::new(RustpotterDetector::new(wakewords, config)?)),
+        #[cfg(feature = "openwakeword")]
+        WakewordBackend::OpenWakeWord => Ok(Box::new(crate::openwakeword::OpenWakeWordDetector::new(wakewords, config)?)),
+        #[cfg(not(feature = "openwakeword"))]
+        WakewordBackend::OpenWakeWord => bail!("Built without openWakeWord support, rebuild with --features openwakeword"),
+    }
+}
+
+// A trained .rpw model (or .onnx for openWakeWord), or sample clips to build one from on startup
+#[derive(Debug, Clone)]
+pub enum WakewordSource {
+    Model(String),
+    Clips(Vec<String>),
+}
+
+pub struct RustpotterDetector {
+    rustpotter: Rustpotter,
+}
+impl RustpotterDetector {
+    // Wakeword names with where to get them from, detections carry the name
+    pub fn new(wakewords: Vec<(String, WakewordSource)>, config: &WakewordConfig) -> Result<Self> {
+        let mut rustpotter_config = RustpotterConfig::default();
+        rustpotter_config.detector.threshold = config.threshold;
+        rustpotter_config.detector.avg_threshold = config.avg_threshold;
+        rustpotter_config.detector.min_scores = config.min_scores;
+        rustpotter_config.detector.score_mode = match config.score_mode {
+            WakewordScoreMode::Max => ScoreMode::Max,
+            WakewordScoreMode::Avg => ScoreMode::Avg,
+            WakewordScoreMode::Median => ScoreMode::Median,
+        };
+        let mut rustpotter = Rustpotter::new(&rustpotter_config).map_err(|e| anyhow!(e))?;
+        for (name, source) in wakewords {
+            let wakeword = match source {
+                WakewordSource::Model(path) => Wakeword::load_from_file(&path)
+                    .map_err(|e| anyhow!("Failed to load wakeword model {:?}: {}", path, e))?,
+                WakewordSource::Clips(clips) => build(&name, clips)?,
+            };
+            rustpotter.add_wakeword(wakeword);
+        }
+
+        Ok(RustpotterDetector { rustpotter })
+    }
+}
+// No thresholds of its own, so the detector's (from the config) apply
+fn build(name: &str, clips: Vec<String>) -> Result<Wakeword> {
+    Wakeword::new_from_sample_files(name.to_string(), None, None, clips)
+        .map_err(|e| anyhow!("Failed to build wakeword {:?}: {}", name, e))
+}
+
+// Every .wav in a directory, in order
+pub fn wav_clips(dir: &Path) -> Result<Vec<String>> {
+    let mut clips: Vec<String> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read wakeword directory {:?}", dir))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("wav")))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    clips.sort();
+    Ok(clips)
+}
+
+// Build a wakeword from every .wav in a directory and save it as a model
+pub fn train(name: &str, dir: &Path, output: &Path) -> Result<()> {
+    let clips = wav_clips(dir)?;
+    if clips.is_empty() {
+        bail!("No .wav clips in {:?}", dir);
+    }
+
+    println!("Training {:?} from {} clips...", name, clips.len());
+    build(name, clips)?
+        .save_to_file(&output.to_string_lossy())
+        .map_err(|e| anyhow!("Failed to save {:?}: {}", output, e))?;
+    println!("Saved {:?}, set wakeword.model to use it", output);
+    Ok(())
+}
+
+impl WakeDetector for RustpotterDetector {
+    fn samples_per_frame(&self) -> usize {
+        self.rustpotter.get_samples_per_frame()
+    }
+    fn process(&mut self, frame: &[f32]) -> Option<Detection> {
+        self.rustpotter.process_f32(frame).as_ref().map(Detection::from)
+    }
+    fn partial(&self) -> Option<Detection> {
+        self.rustpotter.get_partial_detection().map(Detection::from)
+    }
+}
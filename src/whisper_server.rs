@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use anyhow::{Result, Context, bail};
+use reqwest::blocking::multipart::{Form, Part};
+use serde_json::Value;
+
+use crate::cloud_stt::encode_wav;
+use crate::config::{WhisperConfig, WhisperServerConfig};
+use crate::transcribe::{Transcriber, Transcription};
+
+// Posts each command to a whisper.cpp server, for running a bigger model on
+// another machine
+pub struct WhisperServerTranscriber {
+    config: WhisperServerConfig,
+    language: String,
+}
+impl WhisperServerTranscriber {
+    pub fn new(config: &WhisperServerConfig, whisper: &WhisperConfig) -> Self {
+        WhisperServerTranscriber { config: config.clone(), language: whisper.language.clone() }
+    }
+}
+impl Transcriber for WhisperServerTranscriber {
+    fn transcribe(&mut self, samples: &[f32]) -> Result<Transcription> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .build()?;
+        let form = Form::new()
+            .part("file", Part::bytes(encode_wav(samples)?).file_name("command.wav").mime_str("audio/wav")?)
+            .text("language", self.language.clone())
+            .text("response_format", "verbose_json");
+
+        let url = format!("{}/inference", self.config.url.trim_end_matches('/'));
+        let response = client.post(&url).multipart(form).send()
+            .with_context(|| format!("Failed to reach whisper server {:?}", url))?;
+        // Errors don't always come back as JSON
+        let status = response.status();
+        if !status.is_success() {
+            bail!("Whisper server: {}: {}", status, response.text().unwrap_or_default());
+        }
+        let body: Value = response.json().context("Invalid response from whisper server")?;
+
+        // Older servers only know plain json, which is just the text
+        let Some(segments) = body["segments"].as_array() else {
+            return Ok(Transcription::text(vec![body["text"].as_str().unwrap_or_default().to_string()]));
+        };
+        Ok(Transcription {
+            segments: segments.iter().map(|segment| segment["text"].as_str().unwrap_or_default().to_string()).collect(),
+            language: body["language"].as_str().map(str::to_string),
+            confidence: None,
+            times: segments.iter()
+                .map(|segment| (segment["start"].as_f64().unwrap_or(0.) as f32, segment["end"].as_f64().unwrap_or(0.) as f32))
+                .collect(),
+        })
+    }
+}